@@ -0,0 +1,151 @@
+//! This example shows a server-rendered HTML form that carries its CSRF token in
+//! a hidden input. Unlike the other examples, the app is also driven end to end
+//! by the tests at the bottom of this file, so it doubles as a regression test
+//! for the form flow. Run them with `cargo test --example form_app`.
+//!
+//! Remember that these examples are isolated, may not consider all security
+//! aspects, such as the strengths and weaknesses of the double-submit technique
+//! used.
+
+use actix_csrf::extractor::{Csrf, CsrfGuarded, CsrfToken};
+use actix_csrf::CsrfMiddleware;
+use actix_web::http::Method;
+use actix_web::web::{Form, ServiceConfig};
+use actix_web::HttpResponse;
+use actix_web::{get, post, App, HttpServer, Responder};
+use rand::prelude::StdRng;
+use serde::{Deserialize, Serialize};
+
+/// Builds the middleware used by both the server and the tests.
+fn csrf() -> CsrfMiddleware<StdRng> {
+    // Use the default CSRF token settings, and set the token when the comment
+    // form is requested.
+    CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/comment")
+}
+
+/// Registers the routes used by both the server and the tests.
+fn routes(cfg: &mut ServiceConfig) {
+    cfg.service(comment_ui).service(comment);
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    HttpServer::new(|| App::new().wrap(csrf()).configure(routes))
+        .bind(("127.0.0.1", 8080))?
+        .run()
+        .await
+}
+
+/// Returns a comment form with the CSRF token in a hidden input.
+#[get("/comment")]
+async fn comment_ui(token: CsrfToken) -> impl Responder {
+    let body = format!(
+        r#"
+        <!DOCTYPE html>
+        <html>
+        <head><meta charset="UTF-8"><title>Example</title></head>
+        <body>
+        <form action="/comment" method="post">
+            <input type="hidden" name="csrf_token" value="{}" />
+            <label>Comment:<input type="text" name="comment" /></label>
+            <button type="submit">Post</button>
+        </form>
+        </body>
+        </html>
+        "#,
+        token.get()
+    );
+
+    HttpResponse::Ok().body(body)
+}
+
+#[derive(Deserialize, Serialize)]
+struct CommentForm {
+    csrf_token: CsrfToken,
+    comment: String,
+}
+
+impl CsrfGuarded for CommentForm {
+    fn csrf_token(&self) -> &CsrfToken {
+        &self.csrf_token
+    }
+}
+
+/// Accepts the comment form once its CSRF token has been validated.
+#[post("/comment")]
+async fn comment(form: Csrf<Form<CommentForm>>) -> impl Responder {
+    HttpResponse::Ok().body(form.into_inner().into_inner().comment)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    use actix_web::cookie::Cookie;
+    use actix_web::http::header::SET_COOKIE;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    fn form(token: &str) -> CommentForm {
+        CommentForm {
+            csrf_token: CsrfToken::test_create(token.to_owned()),
+            comment: "hello".to_owned(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn form_round_trip() {
+        let app = init_service(App::new().wrap(csrf()).configure(routes)).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/comment").to_request()).await;
+        let set_cookie = resp.headers().get(SET_COOKIE).expect("cookie to be set");
+        let cookie = Cookie::from_str(set_cookie.to_str().unwrap())
+            .unwrap()
+            .into_owned();
+        let body = read_body(resp).await;
+        let hidden_input = format!(r#"value="{}""#, cookie.value());
+        assert!(String::from_utf8_lossy(&body).contains(&hidden_input));
+
+        let req = TestRequest::post()
+            .uri("/comment")
+            .cookie(cookie.clone())
+            .set_form(form(cookie.value()))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(read_body(resp).await, "hello");
+    }
+
+    #[actix_web::test]
+    async fn form_without_cookie_is_rejected() {
+        let app = init_service(App::new().wrap(csrf()).configure(routes)).await;
+
+        let req = TestRequest::post()
+            .uri("/comment")
+            .set_form(form("forged"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_web::test]
+    async fn form_with_mismatched_token_is_rejected() {
+        let app = init_service(App::new().wrap(csrf()).configure(routes)).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/comment").to_request()).await;
+        let set_cookie = resp.headers().get(SET_COOKIE).expect("cookie to be set");
+        let cookie = Cookie::from_str(set_cookie.to_str().unwrap())
+            .unwrap()
+            .into_owned();
+
+        let req = TestRequest::post()
+            .uri("/comment")
+            .cookie(cookie)
+            .set_form(form("forged"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,132 @@
+//! This example shows a single page application that fetches its CSRF token
+//! from a JSON endpoint and sends it back in a custom header. Unlike the other
+//! examples, the app is also driven end to end by the tests at the bottom of
+//! this file, so it doubles as a regression test for the header flow. Run them
+//! with `cargo test --example spa_api`.
+//!
+//! Remember that these examples are isolated, may not consider all security
+//! aspects, such as the strengths and weaknesses of the double-submit technique
+//! used.
+
+use actix_csrf::extractor::{Csrf, CsrfHeader, CsrfToken};
+use actix_csrf::CsrfMiddleware;
+use actix_web::http::Method;
+use actix_web::web::{Json, ServiceConfig};
+use actix_web::HttpResponse;
+use actix_web::{get, post, App, HttpServer, Responder};
+use rand::prelude::StdRng;
+use serde::{Deserialize, Serialize};
+
+/// Builds the middleware used by both the server and the tests.
+fn csrf() -> CsrfMiddleware<StdRng> {
+    // The token is handed to the application by the token endpoint, so the
+    // cookie can stay inaccessible to Javascript.
+    CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/csrf")
+}
+
+/// Registers the routes used by both the server and the tests.
+fn routes(cfg: &mut ServiceConfig) {
+    cfg.service(token).service(increment);
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    HttpServer::new(|| App::new().wrap(csrf()).configure(routes))
+        .bind(("127.0.0.1", 8080))?
+        .run()
+        .await
+}
+
+#[derive(Deserialize, Serialize)]
+struct TokenResponse {
+    token: String,
+}
+
+/// Hands the CSRF token that is also set as a cookie to the application.
+#[get("/csrf")]
+async fn token(token: CsrfToken) -> impl Responder {
+    HttpResponse::Ok().json(TokenResponse {
+        token: token.into_inner(),
+    })
+}
+
+#[derive(Deserialize, Serialize)]
+struct Counter {
+    count: usize,
+}
+
+/// Increments a counter once the `Csrf-Token` header has been validated.
+#[post("/api/increment")]
+async fn increment(_: Csrf<CsrfHeader>, json: Json<Counter>) -> impl Responder {
+    HttpResponse::Ok().json(Counter {
+        count: json.count + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    use actix_web::cookie::Cookie;
+    use actix_web::http::header::SET_COOKIE;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+
+    #[actix_web::test]
+    async fn header_round_trip() {
+        let app = init_service(App::new().wrap(csrf()).configure(routes)).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/csrf").to_request()).await;
+        let set_cookie = resp.headers().get(SET_COOKIE).expect("cookie to be set");
+        let cookie = Cookie::from_str(set_cookie.to_str().unwrap())
+            .unwrap()
+            .into_owned();
+        let body: TokenResponse = read_body_json(resp).await;
+        assert_eq!(body.token, cookie.value());
+
+        let req = TestRequest::post()
+            .uri("/api/increment")
+            .cookie(cookie)
+            .insert_header(("Csrf-Token", body.token))
+            .set_json(Counter { count: 1 })
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Counter = read_body_json(resp).await;
+        assert_eq!(body.count, 2);
+    }
+
+    #[actix_web::test]
+    async fn missing_header_is_rejected() {
+        let app = init_service(App::new().wrap(csrf()).configure(routes)).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/csrf").to_request()).await;
+        let set_cookie = resp.headers().get(SET_COOKIE).expect("cookie to be set");
+        let cookie = Cookie::from_str(set_cookie.to_str().unwrap())
+            .unwrap()
+            .into_owned();
+
+        let req = TestRequest::post()
+            .uri("/api/increment")
+            .cookie(cookie)
+            .set_json(Counter { count: 1 })
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_web::test]
+    async fn header_without_cookie_is_rejected() {
+        let app = init_service(App::new().wrap(csrf()).configure(routes)).await;
+
+        let req = TestRequest::post()
+            .uri("/api/increment")
+            .insert_header(("Csrf-Token", "forged"))
+            .set_json(Counter { count: 1 })
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
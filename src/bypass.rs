@@ -0,0 +1,24 @@
+//! A switch that turns off token validation for the current thread, set by
+//! [`with_csrf_disabled`](crate::test::with_csrf_disabled). Without the
+//! `test-helpers` feature, validation can never be turned off.
+
+#[cfg(feature = "test-helpers")]
+use std::cell::Cell;
+
+#[cfg(feature = "test-helpers")]
+thread_local! {
+    /// Whether validation is turned off on this thread.
+    pub static DISABLED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Checks if validation is turned off on this thread.
+#[cfg(feature = "test-helpers")]
+pub fn is_disabled() -> bool {
+    DISABLED.with(Cell::get)
+}
+
+/// Checks if validation is turned off on this thread.
+#[cfg(not(feature = "test-helpers"))]
+pub const fn is_disabled() -> bool {
+    false
+}
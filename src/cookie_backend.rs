@@ -0,0 +1,130 @@
+//! Where the CSRF cookie is read from and written to.
+
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest};
+use cookie::Cookie;
+use tracing::error;
+
+/// Reads and writes the CSRF cookie, so that it can be stored somewhere other
+/// than the `Cookie` and `Set-Cookie` headers, such as a header pair managed by
+/// a proxy.
+///
+/// The middleware writes the token cookie, and the removals of legacy cookies,
+/// through the backend, and both the middleware and the extractors read the
+/// cookie through it. Signing, legacy cookie names, and ambiguous cookie
+/// detection all apply to the values the backend reads. The default backend is
+/// [`CookieHeaders`].
+///
+/// ```
+/// use actix_csrf::{CookieBackend, CsrfMiddleware};
+/// use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+/// use actix_web::HttpRequest;
+/// use cookie::Cookie;
+/// use rand::rngs::StdRng;
+///
+/// /// Stores the token in the `x-csrf-cookie` and `x-set-csrf-cookie` headers.
+/// struct HeaderPair;
+///
+/// impl CookieBackend for HeaderPair {
+///     fn read(&self, req: &HttpRequest, _name: &str) -> Option<String> {
+///         let value = req.headers().get("x-csrf-cookie")?;
+///         value.to_str().ok().map(str::to_owned)
+///     }
+///
+///     fn write(&self, headers: &mut HeaderMap, cookie: &Cookie<'_>) {
+///         if let Ok(value) = HeaderValue::from_str(cookie.value()) {
+///             headers.insert(HeaderName::from_static("x-set-csrf-cookie"), value);
+///         }
+///     }
+/// }
+///
+/// let csrf = CsrfMiddleware::<StdRng>::new().cookie_backend(HeaderPair);
+/// ```
+pub trait CookieBackend {
+    /// Reads the value of the cookie with the name from the request, if any.
+    fn read(&self, req: &HttpRequest, name: &str) -> Option<String>;
+
+    /// Reads the values of all cookies with the name from the request, in the
+    /// order they were sent. This is used to detect ambiguous cookies, and
+    /// defaults to the single value returned by [`read`](Self::read).
+    fn read_all(&self, req: &HttpRequest, name: &str) -> Vec<String> {
+        self.read(req, name).into_iter().collect()
+    }
+
+    /// Writes the cookie to the response headers. The cookie carries the value
+    /// and the configured attributes; a cookie with a zero `Max-Age` removes
+    /// the stored cookie.
+    fn write(&self, headers: &mut HeaderMap, cookie: &Cookie<'_>);
+}
+
+/// The default [`CookieBackend`], which reads the `Cookie` header and appends
+/// a `Set-Cookie` header.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub struct CookieHeaders;
+
+impl CookieBackend for CookieHeaders {
+    fn read(&self, req: &HttpRequest, name: &str) -> Option<String> {
+        req.cookie(name).map(|cookie| cookie.value().to_owned())
+    }
+
+    fn read_all(&self, req: &HttpRequest, name: &str) -> Vec<String> {
+        req.cookies().map_or_else(
+            |_| Vec::new(),
+            |cookies| {
+                cookies
+                    .iter()
+                    .filter(|v| v.name() == name)
+                    .map(|v| v.value().to_owned())
+                    .collect()
+            },
+        )
+    }
+
+    fn write(&self, headers: &mut HeaderMap, cookie: &Cookie<'_>) {
+        if let Some(value) = to_header_value(cookie) {
+            headers.append(header::SET_COOKIE, value);
+        }
+    }
+}
+
+/// Converts the cookie to a `Set-Cookie` header value, logging an error if it
+/// is not a valid header value.
+fn to_header_value(cookie: &Cookie) -> Option<HeaderValue> {
+    HeaderValue::from_str(&cookie.to_string())
+        .map_err(|_| {
+            error!(
+                "The CSRF cookie is not a valid header value, so it will not be set. Check the \
+                configured cookie name and domain."
+            );
+        })
+        .ok()
+}
+
+/// A configured cookie backend. It is stored in the request extensions so that
+/// the extractors read the cookie the same way as the middleware.
+#[derive(Clone)]
+pub struct SharedCookieBackend(pub Rc<dyn CookieBackend>);
+
+impl SharedCookieBackend {
+    /// Returns the backend configured for the request, if any.
+    pub fn get(req: &HttpRequest) -> Option<Self> {
+        req.extensions().get::<Self>().cloned()
+    }
+}
+
+impl PartialEq for SharedCookieBackend {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::as_ptr(&self.0).cast::<()>() == Rc::as_ptr(&other.0).cast::<()>()
+    }
+}
+
+impl Eq for SharedCookieBackend {}
+
+impl Debug for SharedCookieBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedCookieBackend")
+    }
+}
@@ -0,0 +1,54 @@
+//! Counters emitted through the [`metrics`](https://docs.rs/metrics) crate when
+//! the `metrics` feature is enabled. Without the feature, these do nothing.
+
+// The functions are only empty, and therefore const, without the feature.
+#![cfg_attr(not(feature = "metrics"), allow(clippy::missing_const_for_fn))]
+
+use crate::CsrfError;
+
+/// Counts a CSRF cookie issued with a token, as `csrf.issued`.
+pub fn issued() {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("csrf.issued");
+}
+
+/// Counts a `Secure` cookie issued, or refused, over plain HTTP, as
+/// `csrf.insecure_transport`.
+pub fn insecure_transport() {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("csrf.insecure_transport");
+}
+
+/// Counts a request that passed validation, as `csrf.validated`.
+pub fn validated() {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("csrf.validated");
+}
+
+/// Counts a request that failed validation, as `csrf.rejected` with a `reason`
+/// label derived from the error.
+pub fn rejected(error: CsrfError) {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("csrf.rejected", "reason" => reason(error));
+    #[cfg(not(feature = "metrics"))]
+    let _ = error;
+}
+
+/// Counts a request whose underlying extractor failed for a reason other than a
+/// CSRF error, as `csrf.rejected` with the `extractor_error` reason.
+pub fn extractor_failed() {
+    #[cfg(feature = "metrics")]
+    metrics::increment_counter!("csrf.rejected", "reason" => "extractor_error");
+}
+
+#[cfg(feature = "metrics")]
+const fn reason(error: CsrfError) -> &'static str {
+    match error {
+        CsrfError::TokenMismatch => "token_mismatch",
+        CsrfError::MissingCookie => "missing_cookie",
+        CsrfError::MissingToken => "missing_token",
+        CsrfError::InvalidToken => "invalid_token",
+        CsrfError::TokenNotIssued => "token_not_issued",
+        CsrfError::AmbiguousCookie => "ambiguous_cookie",
+    }
+}
@@ -1,25 +1,66 @@
 //! Contains various extractors related to CSRF tokens.
 
+use std::any::Any;
 use std::future::{ready, Future, Ready};
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+use crate::cookie_backend::{CookieBackend, CookieHeaders, SharedCookieBackend};
+use crate::token_codec::SharedTokenCodec;
 use crate::{
-    host_prefix, secure_prefix, CsrfError, DEFAULT_CSRF_COOKIE_NAME, DEFAULT_CSRF_TOKEN_NAME,
+    bypass, counters, host_prefix, secure_prefix, CsrfError, LegacyCookie, OnValidated,
+    RejectAmbiguousCookie, ResolvedCookieName, ValidationDisabled, DEFAULT_CSRF_COOKIE_NAME,
+    DEFAULT_CSRF_HEADER_NAME,
 };
 
+use actix_web::cookie::Cookie;
 use actix_web::dev::Payload;
-use actix_web::http::header::HeaderName;
+use actix_web::http::header::{self, HeaderName, InvalidHeaderName};
 use actix_web::{FromRequest, HttpMessage, HttpRequest};
 use serde::de::{Error, Visitor};
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tracing::debug;
+
+const DEFAULT_CSRF_SUBPROTOCOL_PREFIX: &str = "csrf.";
+
+/// The maximum length of a submitted CSRF token, in bytes. Longer tokens are
+/// rejected before being copied, which bounds the cost of hostile input.
+pub const MAX_TOKEN_LENGTH: usize = 512;
+
+/// Checks if a submitted token is non-empty, short enough, and only consists of
+/// characters allowed in a cookie value, as defined in RFC 6265.
+fn is_plausible_token(token: &[u8]) -> bool {
+    !token.is_empty()
+        && token.len() <= MAX_TOKEN_LENGTH
+        && token
+            .iter()
+            .all(|b| b.is_ascii_graphic() && !b"\",;\\".contains(b))
+}
+
+/// Copies a submitted token, rejecting it if it is implausible.
+fn parse_token(token: &str) -> Result<CsrfToken, CsrfError> {
+    if is_plausible_token(token.as_bytes()) {
+        Ok(CsrfToken(token.to_owned()))
+    } else {
+        Err(CsrfError::InvalidToken)
+    }
+}
 
 /// Extractor to get the CSRF header from the request.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct CsrfHeader(CsrfToken);
 
 impl CsrfHeader {
+    /// Used for testing purposes only. Wraps the token without reading it from
+    /// a request, so that handlers can be called directly in unit tests.
+    #[must_use]
+    #[doc(hidden)]
+    pub const fn test_create(token: CsrfToken) -> Self {
+        Self(token)
+    }
+
     /// Checks if the header matches the CSRF header.
     pub fn validate(&self, header_value: impl AsRef<str>) -> bool {
         self.0.as_ref() == header_value.as_ref()
@@ -39,16 +80,17 @@ impl FromRequest for CsrfHeader {
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         let header_name = req
             .app_data::<CsrfHeaderConfig>()
-            .map_or(DEFAULT_CSRF_TOKEN_NAME, |v| v.header_name.as_ref());
+            .map_or(&DEFAULT_CSRF_HEADER_NAME, |v| &v.header_name);
 
         let resp = req
             .headers()
             .get(header_name)
             .map_or(Err(CsrfError::MissingCookie), |header| {
-                match header.to_str() {
-                    Ok(header) => Ok(Self(CsrfToken(header.to_owned()))),
-                    Err(_) => Err(CsrfError::MissingToken),
-                }
+                header
+                    .to_str()
+                    .map_err(|_| CsrfError::MissingToken)
+                    .and_then(parse_token)
+                    .map(Self)
             });
 
         ready(resp)
@@ -70,7 +112,7 @@ pub struct CsrfHeaderConfig {
 impl Default for CsrfHeaderConfig {
     fn default() -> Self {
         Self {
-            header_name: HeaderName::from_static(DEFAULT_CSRF_TOKEN_NAME),
+            header_name: DEFAULT_CSRF_HEADER_NAME.clone(),
         }
     }
 }
@@ -80,6 +122,127 @@ impl CsrfHeaderConfig {
     pub const fn new(header_name: HeaderName) -> Self {
         Self { header_name }
     }
+
+    /// Sets the header name to read the CSRF token from, parsing it from a
+    /// string. The name is normalized to lowercase, so mixed-case names such as
+    /// `X-Csrf-Token` match regardless of how the client or protocol version
+    /// spells the header.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the provided name is not a valid header name.
+    pub fn try_new(header_name: impl AsRef<str>) -> Result<Self, InvalidHeaderName> {
+        HeaderName::from_bytes(header_name.as_ref().as_bytes()).map(Self::new)
+    }
+
+    /// Reads the CSRF token from the `X-CSRFToken` header, as Django does.
+    #[must_use]
+    pub const fn django_compat() -> Self {
+        Self::new(HeaderName::from_static("x-csrftoken"))
+    }
+}
+
+/// Extractor to get the CSRF token from the `Sec-WebSocket-Protocol` header of
+/// a WebSocket handshake.
+///
+/// Browsers cannot set custom headers on WebSocket handshakes, so a common
+/// workaround is to smuggle the CSRF token as a fake subprotocol, such as
+/// `new WebSocket(url, ["chat", "csrf." + token])`. This extractor scans the
+/// offered subprotocols for the entry starting with the configured prefix
+/// (`csrf.` by default) and uses the remainder as the token. Offering more than
+/// one such entry is rejected with [`CsrfError::InvalidToken`].
+///
+/// The CSRF entry must never be echoed back to the client as the selected
+/// subprotocol. Use [`protocols`](Self::protocols) to pick the subprotocol to
+/// respond with, as it only contains the real subprotocols.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct CsrfSubprotocol {
+    token: CsrfToken,
+    protocols: Vec<String>,
+}
+
+impl CsrfSubprotocol {
+    /// Used for testing purposes only. Wraps the token and the remaining
+    /// subprotocols without reading them from a request, so that handlers can
+    /// be called directly in unit tests.
+    #[must_use]
+    #[doc(hidden)]
+    pub const fn test_create(token: CsrfToken, protocols: Vec<String>) -> Self {
+        Self { token, protocols }
+    }
+
+    /// Checks if the input matches the token in the subprotocol list.
+    pub fn validate(&self, token: impl AsRef<str>) -> bool {
+        self.token.as_ref() == token.as_ref()
+    }
+
+    /// Returns the offered subprotocols, excluding the one carrying the CSRF
+    /// token.
+    #[must_use]
+    pub fn protocols(&self) -> &[String] {
+        &self.protocols
+    }
+
+    fn from_request_sync(req: &HttpRequest) -> Result<Self, CsrfError> {
+        let prefix = req
+            .app_data::<CsrfSubprotocolConfig>()
+            .map_or(DEFAULT_CSRF_SUBPROTOCOL_PREFIX, |v| v.prefix.as_ref());
+
+        let mut token = None;
+        let mut protocols = Vec::new();
+        for header in req.headers().get_all(header::SEC_WEBSOCKET_PROTOCOL) {
+            let header = header.to_str().map_err(|_| CsrfError::MissingToken)?;
+            for protocol in header.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+                match protocol.strip_prefix(prefix) {
+                    Some(_) if token.is_some() => return Err(CsrfError::InvalidToken),
+                    Some(value) => token = Some(parse_token(value)?),
+                    None => protocols.push(protocol.to_owned()),
+                }
+            }
+        }
+
+        token
+            .ok_or(CsrfError::MissingToken)
+            .map(|token| Self { token, protocols })
+    }
+}
+
+impl CsrfGuarded for CsrfSubprotocol {
+    fn csrf_token(&self) -> &CsrfToken {
+        &self.token
+    }
+}
+
+impl FromRequest for CsrfSubprotocol {
+    type Error = CsrfError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::from_request_sync(req))
+    }
+}
+
+/// Configuration struct for [`CsrfSubprotocol`].
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct CsrfSubprotocolConfig {
+    prefix: String,
+}
+
+impl Default for CsrfSubprotocolConfig {
+    fn default() -> Self {
+        Self {
+            prefix: DEFAULT_CSRF_SUBPROTOCOL_PREFIX.to_owned(),
+        }
+    }
+}
+
+impl CsrfSubprotocolConfig {
+    /// Sets the prefix identifying the subprotocol entry that carries the CSRF
+    /// token.
+    #[must_use]
+    pub const fn new(prefix: String) -> Self {
+        Self { prefix }
+    }
 }
 
 /// Extractor to get the CSRF cookie from the request.
@@ -93,16 +256,67 @@ impl CsrfCookie {
     }
 
     fn from_request_sync(req: &HttpRequest) -> Result<Self, CsrfError> {
-        let cookie_name = req
-            .app_data::<CsrfCookieConfig>()
-            .map_or(DEFAULT_CSRF_COOKIE_NAME, |v| v.cookie_name.as_ref());
+        // The name the middleware used for this request takes precedence.
+        let resolved_name = req
+            .extensions()
+            .get::<ResolvedCookieName>()
+            .map(|v| v.0.clone());
+        let cookie_name = resolved_name.as_deref().unwrap_or_else(|| {
+            req.app_data::<CsrfCookieConfig>()
+                .map_or(DEFAULT_CSRF_COOKIE_NAME, |v| v.cookie_name.as_ref())
+        });
+
+        let shared_backend = SharedCookieBackend::get(req);
+        let backend = shared_backend
+            .as_ref()
+            .map_or(&CookieHeaders as &dyn CookieBackend, |v| &*v.0);
+
+        let strict = req.extensions().contains::<RejectAmbiguousCookie>();
+        if strict && has_conflicting_values(&backend.read_all(req, cookie_name)) {
+            return Err(CsrfError::AmbiguousCookie);
+        }
 
-        req.cookie(cookie_name)
-            .ok_or(CsrfError::MissingCookie)
-            .map(|cookie| Self(cookie.value().to_string()))
+        // Fall back to a legacy cookie accepted by the middleware, if any.
+        backend
+            .read(req, cookie_name)
+            .map(|value| {
+                let cookie = Cookie::new(cookie_name.to_owned(), value);
+                verify_cookie(req, cookie).and_then(|cookie| parse_token(cookie.value()))
+            })
+            .or_else(|| {
+                req.extensions()
+                    .get::<LegacyCookie>()
+                    .map(|v| parse_token(&v.0))
+            })
+            .ok_or(CsrfError::MissingCookie)?
+            .map(|token| Self(token.0))
     }
 }
 
+/// Checks if the values of several cookies with the same name differ.
+fn has_conflicting_values(values: &[String]) -> bool {
+    values.split_first().map_or(false, |(first, rest)| {
+        rest.iter().any(|value| value != first)
+    })
+}
+
+/// Verifies the CSRF cookie if the middleware signs cookies, returning it with
+/// its signature stripped.
+#[cfg_attr(
+    not(feature = "signed"),
+    allow(clippy::missing_const_for_fn, clippy::unnecessary_wraps)
+)]
+fn verify_cookie(req: &HttpRequest, cookie: Cookie<'static>) -> Result<Cookie<'static>, CsrfError> {
+    #[cfg(feature = "signed")]
+    if let Some(key) = req.extensions().get::<crate::signing::SigningKey>() {
+        return key.verify(cookie).ok_or(CsrfError::InvalidToken);
+    }
+
+    #[cfg(not(feature = "signed"))]
+    let _ = req;
+    Ok(cookie)
+}
+
 impl FromRequest for CsrfCookie {
     type Error = CsrfError;
     type Future = Ready<Result<Self, Self::Error>>;
@@ -118,7 +332,8 @@ impl AsRef<str> for CsrfCookie {
     }
 }
 
-/// Configuration struct for [`CsrfCookie`].
+/// Configuration struct for [`CsrfCookie`]. Requests handled by the middleware
+/// use the middleware's cookie name instead.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct CsrfCookieConfig {
     cookie_name: String,
@@ -172,6 +387,10 @@ impl CsrfCookieConfig {
 }
 
 /// Extractor to get the CSRF token that will be set as a cookie.
+///
+/// Extracting this fails with [`CsrfError::TokenNotIssued`], an internal server
+/// error, if the middleware did not issue a token for the request. Extract an
+/// `Option<CsrfToken>` instead on routes where a token may not be issued.
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct CsrfToken(pub(crate) String);
 
@@ -201,7 +420,7 @@ impl<'de> Deserialize<'de> for CsrfToken {
             where
                 E: Error,
             {
-                Ok(CsrfToken(v.to_owned()))
+                parse_token(v).map_err(E::custom)
             }
         }
 
@@ -235,7 +454,7 @@ impl CsrfToken {
         req.extensions()
             .get::<Self>()
             .cloned()
-            .ok_or(CsrfError::MissingToken)
+            .ok_or(CsrfError::TokenNotIssued)
     }
 }
 
@@ -287,6 +506,15 @@ impl FromRequest for CsrfToken {
 pub struct Csrf<Inner>(Inner);
 
 impl<Inner> Csrf<Inner> {
+    /// Used for testing purposes only. Wraps the value without validating it,
+    /// so that handlers can be called directly in unit tests, without going
+    /// through the HTTP layer.
+    #[must_use]
+    #[doc(hidden)]
+    pub const fn test_create(inner: Inner) -> Self {
+        Self(inner)
+    }
+
     /// Deconstruct to an inner value
     #[must_use]
     pub fn into_inner(self) -> Inner {
@@ -311,6 +539,7 @@ impl<Inner> DerefMut for Csrf<Inner> {
 impl<Inner> FromRequest for Csrf<Inner>
 where
     Inner: FromRequest + CsrfGuarded,
+    Inner::Error: 'static,
 {
     type Error = CsrfExtractorError<Inner::Error>;
     type Future = CsrfExtractorFuture<Inner::Future>;
@@ -318,6 +547,12 @@ where
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
         CsrfExtractorFuture {
             csrf_token: CsrfCookie::from_request_sync(req),
+            disabled: bypass::is_disabled() || req.extensions().contains::<ValidationDisabled>(),
+            on_validated: req
+                .extensions()
+                .get::<OnValidated>()
+                .map(|on_validated| (on_validated.clone(), req.clone())),
+            token_codec: SharedTokenCodec::get(req).map(|token_codec| (token_codec, req.clone())),
             inner: Box::pin(Inner::from_request(req, payload)),
         }
     }
@@ -344,33 +579,105 @@ derive_csrf_guarded!(actix_web::web::Json<T>);
 /// cannot be constructed normally.
 pub struct CsrfExtractorFuture<Fut> {
     csrf_token: Result<CsrfCookie, CsrfError>,
+    disabled: bool,
+    on_validated: Option<(OnValidated, HttpRequest)>,
+    token_codec: Option<(SharedTokenCodec, HttpRequest)>,
     inner: Pin<Box<Fut>>,
 }
 
+impl<Fut> CsrfExtractorFuture<Fut> {
+    /// Compares the secrets of the cookie and the submitted token in constant
+    /// time, decoding them first if a token codec was configured.
+    fn secrets_match(&self, cookie: &str, submitted: &str) -> Result<bool, CsrfError> {
+        Ok(match &self.token_codec {
+            Some((codec, req)) => {
+                let cookie = codec.0.decode(req, cookie)?;
+                let submitted = codec.0.decode(req, submitted)?;
+                constant_time_eq(&cookie, &submitted)
+            }
+            None => constant_time_eq(cookie, submitted),
+        })
+    }
+}
+
 impl<Fut, FutOut, FutErr> Future for CsrfExtractorFuture<Fut>
 where
     Fut: Future<Output = Result<FutOut, FutErr>>,
     FutOut: CsrfGuarded,
+    FutErr: 'static,
 {
     type Output = Result<Csrf<FutOut>, CsrfExtractorError<FutErr>>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.inner.as_mut().poll(cx) {
+            Poll::Ready(Ok(out)) if self.disabled => Poll::Ready(Ok(Csrf(out))),
             Poll::Ready(Ok(out)) => {
-                if let Ok(ref token) = self.csrf_token {
-                    if out.csrf_token().as_ref() == token.as_ref() {
-                        return Poll::Ready(Ok(Csrf(out)));
-                    }
+                match self.csrf_token {
+                    Ok(ref token) => match self
+                        .secrets_match(token.as_ref(), out.csrf_token().as_ref())
+                    {
+                        Ok(true) => {
+                            counters::validated();
+                            if let Some((on_validated, req)) = self.on_validated.take() {
+                                (on_validated.0)(&req);
+                            }
+                            return Poll::Ready(Ok(Csrf(out)));
+                        }
+                        Ok(false) => {
+                            if is_possibly_truncated(token.as_ref(), out.csrf_token().as_ref()) {
+                                debug!(
+                                    "The submitted CSRF token is a strict prefix of the cookie \
+                                    value; a proxy may be truncating the header"
+                                );
+                            }
+
+                            counters::rejected(CsrfError::TokenMismatch);
+                        }
+                        Err(e) => counters::rejected(e),
+                    },
+                    Err(e) => counters::rejected(e),
                 }
 
                 Poll::Ready(Err(CsrfExtractorError::InvalidToken))
             }
-            Poll::Ready(Err(e)) => Poll::Ready(Err(CsrfExtractorError::Inner(e))),
+            Poll::Ready(Err(e)) => {
+                match as_csrf_error(&e) {
+                    Some(csrf_error) => counters::rejected(csrf_error),
+                    None => counters::extractor_failed(),
+                }
+                Poll::Ready(Err(CsrfExtractorError::Inner(e)))
+            }
             Poll::Pending => Poll::Pending,
         }
     }
 }
 
+/// Returns the CSRF error behind an underlying extractor's error, if any. Token
+/// extractors fail with a [`CsrfError`], while others, such as
+/// [`Form`](actix_web::web::Form), fail with an [`actix_web::Error`] that may
+/// wrap one.
+fn as_csrf_error<E: 'static>(error: &E) -> Option<CsrfError> {
+    let error: &dyn Any = error;
+    error.downcast_ref::<CsrfError>().copied().or_else(|| {
+        error
+            .downcast_ref::<actix_web::Error>()
+            .and_then(|error| error.as_error::<CsrfError>().copied())
+    })
+}
+
+/// Compares the strings in constant time, so that a rejection does not reveal
+/// how much of a guessed token was right.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Checks if the submitted token looks like a truncated copy of the cookie
+/// value. This is only used to diagnose rejections; such tokens are never
+/// accepted.
+fn is_possibly_truncated(cookie: &str, submitted: &str) -> bool {
+    !submitted.is_empty() && submitted.len() < cookie.len() && cookie.starts_with(submitted)
+}
+
 /// This trait represents types who have a field that represents a CSRF token.
 ///
 /// This trait is required on an underlying type for the [`Csrf`] extractor to
@@ -411,11 +718,12 @@ mod tests {
 
     use actix_web::http::header;
     use actix_web::test::TestRequest;
+    use tracing_test::traced_test;
 
     #[tokio::test]
     async fn extract_from_header() -> Result<(), Box<dyn Error>> {
         let req = TestRequest::default()
-            .insert_header((DEFAULT_CSRF_TOKEN_NAME, "sometoken"))
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "sometoken"))
             .to_http_request();
         let token = CsrfHeader::extract(&req).await?;
         assert!(token.validate("sometoken"));
@@ -446,6 +754,194 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn extract_from_default_header_config() -> Result<(), Box<dyn Error>> {
+        let req = TestRequest::default()
+            .app_data(CsrfHeaderConfig::default())
+            .insert_header(("Csrf-Token", "sometoken"))
+            .to_http_request();
+        let token = CsrfHeader::extract(&req).await?;
+        assert!(token.validate("sometoken"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extract_from_mixed_case_header_config() -> Result<(), Box<dyn Error>> {
+        let req = TestRequest::default()
+            .app_data(CsrfHeaderConfig::try_new("X-My-Csrf-Token")?)
+            .insert_header(("x-my-csrf-token", "sometoken"))
+            .to_http_request();
+        let token = CsrfHeader::extract(&req).await?;
+        assert!(token.validate("sometoken"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extract_from_split_cookie_headers() -> Result<(), Box<dyn Error>> {
+        let req = TestRequest::default()
+            .append_header((header::COOKIE, "unrelated=value"))
+            .append_header((
+                header::COOKIE,
+                format!("other=value; {DEFAULT_CSRF_COOKIE_NAME}=sometoken"),
+            ))
+            .to_http_request();
+
+        let token = CsrfCookie::extract(&req).await?;
+        assert!(token.validate("sometoken"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extract_from_websocket_subprotocol() -> Result<(), Box<dyn Error>> {
+        let req = TestRequest::default()
+            .insert_header((
+                header::COOKIE,
+                format!("{DEFAULT_CSRF_COOKIE_NAME}=sometoken"),
+            ))
+            .insert_header((header::SEC_WEBSOCKET_PROTOCOL, "chat, csrf.sometoken"))
+            .to_http_request();
+
+        let protocol = Csrf::<CsrfSubprotocol>::extract(&req)
+            .await
+            .map_err(|_| "subprotocol token did not validate")?;
+        assert!(protocol.validate("sometoken"));
+        assert_eq!(protocol.protocols(), ["chat"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn duplicate_websocket_subprotocol_tokens_are_rejected() {
+        let req = TestRequest::default()
+            .insert_header((
+                header::SEC_WEBSOCKET_PROTOCOL,
+                "chat, csrf.sometoken, csrf.othertoken",
+            ))
+            .to_http_request();
+        assert_eq!(
+            CsrfSubprotocol::extract(&req).await,
+            Err(CsrfError::InvalidToken)
+        );
+    }
+
+    #[tokio::test]
+    async fn not_found_websocket_subprotocol() {
+        let req = TestRequest::default()
+            .insert_header((header::SEC_WEBSOCKET_PROTOCOL, "chat, superchat"))
+            .to_http_request();
+        let token = CsrfSubprotocol::extract(&req).await;
+        assert!(token.is_err());
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn truncated_token_is_rejected() {
+        let req = TestRequest::default()
+            .insert_header((
+                header::COOKIE,
+                format!("{DEFAULT_CSRF_COOKIE_NAME}=sometoken"),
+            ))
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "some"))
+            .to_http_request();
+
+        assert!(Csrf::<CsrfHeader>::extract(&req).await.is_err());
+        assert!(logs_contain("a proxy may be truncating the header"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn mismatched_token_is_not_diagnosed_as_truncated() {
+        let req = TestRequest::default()
+            .insert_header((
+                header::COOKIE,
+                format!("{DEFAULT_CSRF_COOKIE_NAME}=sometoken"),
+            ))
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "othertoken"))
+            .to_http_request();
+
+        assert!(Csrf::<CsrfHeader>::extract(&req).await.is_err());
+        assert!(!logs_contain("a proxy may be truncating the header"));
+    }
+
+    #[test]
+    fn truncation_diagnostic_requires_strict_prefix() {
+        assert!(!is_possibly_truncated("sometoken", "sometoken"));
+        assert!(!is_possibly_truncated("sometoken", ""));
+        assert!(!is_possibly_truncated("sometoken", "token"));
+        assert!(!is_possibly_truncated("some", "sometoken"));
+    }
+
+    #[tokio::test]
+    async fn oversized_tokens_are_rejected() {
+        let oversized = "a".repeat(MAX_TOKEN_LENGTH + 1);
+        let req = TestRequest::default()
+            .insert_header((
+                header::COOKIE,
+                format!("{DEFAULT_CSRF_COOKIE_NAME}={oversized}"),
+            ))
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), oversized))
+            .to_http_request();
+
+        assert_eq!(
+            CsrfHeader::extract(&req).await,
+            Err(CsrfError::InvalidToken)
+        );
+        assert_eq!(
+            CsrfCookie::extract(&req).await,
+            Err(CsrfError::InvalidToken)
+        );
+    }
+
+    #[test]
+    fn tokens_are_compared_in_full() {
+        assert!(constant_time_eq("token", "token"));
+        assert!(!constant_time_eq("token", "tokem"));
+        assert!(!constant_time_eq("token", "toke"));
+        assert!(!constant_time_eq("", "token"));
+    }
+
+    #[tokio::test]
+    async fn empty_tokens_are_rejected() {
+        let req = TestRequest::default()
+            .insert_header((header::COOKIE, format!("{DEFAULT_CSRF_COOKIE_NAME}=")))
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), ""))
+            .to_http_request();
+
+        assert_eq!(
+            CsrfHeader::extract(&req).await,
+            Err(CsrfError::InvalidToken)
+        );
+        assert_eq!(
+            CsrfCookie::extract(&req).await,
+            Err(CsrfError::InvalidToken)
+        );
+        assert!(Csrf::<CsrfHeader>::extract(&req).await.is_err());
+    }
+
+    #[test]
+    fn token_plausibility() {
+        assert!(is_plausible_token(b"Zm9vYmFy-_"));
+        assert!(is_plausible_token("a".repeat(MAX_TOKEN_LENGTH).as_bytes()));
+        assert!(!is_plausible_token(
+            "a".repeat(MAX_TOKEN_LENGTH + 1).as_bytes()
+        ));
+        assert!(!is_plausible_token(b""));
+        assert!(!is_plausible_token(b"some token"));
+        assert!(!is_plausible_token(b"some;token"));
+    }
+
+    #[tokio::test]
+    async fn handlers_can_be_called_directly() {
+        #[allow(clippy::unused_async)] // mirrors an actix-web handler
+        async fn handler(header: Csrf<CsrfHeader>) -> String {
+            header.into_inner().as_ref().to_owned()
+        }
+
+        let header = CsrfHeader::test_create(CsrfToken::test_create("sometoken".to_owned()));
+        assert_eq!(handler(Csrf::test_create(header)).await, "sometoken");
+    }
+
     #[tokio::test]
     async fn not_found_cookie() {
         let req = TestRequest::default()
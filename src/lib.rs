@@ -106,36 +106,76 @@
 //! `HttpOnly`, and `SameSite=Strict`. However, it is always recommended to
 //! implement more; suggestions are deferred to the [Owasp Cheat Sheet].
 //!
+//! ## Features
+//!
+//! The `metrics` feature increments [`metrics`] counters as tokens are handled,
+//! namely `csrf.issued`, `csrf.validated`, and `csrf.rejected` with a `reason`
+//! label. Requests whose underlying extractor fails for a reason other than a
+//! CSRF error, such as a malformed form, are rejected with the `extractor_error`
+//! reason. `csrf.insecure_transport` counts `Secure` cookies issued over plain
+//! HTTP.
+//!
+//! The `signed` feature allows signing the CSRF cookie with an application key
+//! through [`CsrfMiddleware::signing_key`].
+//!
+//! The `test-helpers` feature exposes the `test` module, with helpers for
+//! testing applications that use the middleware.
+//!
+//! [`metrics`]: https://docs.rs/metrics
 //! [double submit]: https://cheatsheetseries.owasp.org/cheatsheets/Cross-Site_Request_Forgery_Prevention_Cheat_Sheet.html#double-submit-cookie
 //! [Owasp Cheat sheet]: https://cheatsheetseries.owasp.org/cheatsheets/Cross-Site_Request_Forgery_Prevention_Cheat_Sheet.html
 //! [examples]: https://github.com/edward-shen/actix-csrf/tree/master/examples
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashSet;
 use std::default::Default;
 use std::error::Error;
-use std::fmt::Display;
+use std::fmt::{Debug, Display};
 use std::future::{self, Future, Ready};
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use crate::extractor::CsrfToken;
+use crate::token_rng::{
+    DEFAULT_TOKEN_ENTROPY_BITS, MAX_TOKEN_ENTROPY_BITS, MIN_TOKEN_ENTROPY_BITS,
+};
 
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
 use actix_web::error::InternalError;
-use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::uri::{Authority, Uri};
 use actix_web::http::{Method, StatusCode};
-use actix_web::{HttpMessage, HttpResponse, ResponseError};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, ResponseError};
 use cookie::{Cookie, SameSite};
+use cookie_backend::SharedCookieBackend;
 use extractor::CsrfCookieConfig;
 use rand::SeedableRng;
-use tracing::{error, warn};
+use token_codec::SharedTokenCodec;
+use tracing::{debug, error, warn};
 
+mod bypass;
+mod cookie_backend;
+mod counters;
 pub mod extractor;
+#[cfg(feature = "signed")]
+mod signing;
+mod summary;
+#[cfg(feature = "test-helpers")]
+pub mod test;
+mod token_codec;
 mod token_rng;
 
+pub use crate::cookie_backend::{CookieBackend, CookieHeaders};
+pub use crate::summary::CsrfConfigSummary;
+pub use crate::token_codec::{IdentityCodec, TokenCodec};
 pub use crate::token_rng::TokenRng;
+#[cfg(feature = "signed")]
+pub use cookie::Key;
+
+#[cfg(feature = "signed")]
+use crate::signing::SigningKey;
 
 macro_rules! token_name {
     () => {
@@ -159,11 +199,18 @@ macro_rules! secure_prefix {
     };
 }
 
-const DEFAULT_CSRF_TOKEN_NAME: &str = token_name!();
+// Header names are always lowercase on the wire under HTTP/2, and
+// `HeaderName::from_static` rejects anything else, so the default header name
+// is kept in its normalized form.
+static DEFAULT_CSRF_HEADER_NAME: HeaderName = HeaderName::from_static("csrf-token");
 const DEFAULT_CSRF_COOKIE_NAME: &str = concat!(host_prefix!(), token_name!());
 
 /// Internal errors that can happen when processing CSRF tokens.
+///
+/// New variants may be added in minor releases, so matches must include a
+/// wildcard arm.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[non_exhaustive]
 pub enum CsrfError {
     /// The CSRF Token and the token provided in the headers do not match.
     TokenMismatch,
@@ -171,6 +218,16 @@ pub enum CsrfError {
     MissingCookie,
     /// No CSRF Token in the request.
     MissingToken,
+    /// The CSRF Token in the request is empty, too long, or contains invalid
+    /// characters.
+    InvalidToken,
+    /// A handler requires the token to be issued, but the middleware did not
+    /// issue one for this request. This is a server misconfiguration, such as
+    /// a missing middleware or `set_cookie` route.
+    TokenNotIssued,
+    /// The request carries several CSRF Cookies with different values, which
+    /// suggests a cookie was planted, such as from a subdomain.
+    AmbiguousCookie,
 }
 
 impl Display for CsrfError {
@@ -179,19 +236,99 @@ impl Display for CsrfError {
             Self::TokenMismatch => write!(f, "The CSRF Tokens do not match"),
             Self::MissingCookie => write!(f, "The CSRF Cookie is missing"),
             Self::MissingToken => write!(f, "The CSRF Header is missing"),
+            Self::InvalidToken => write!(f, "The CSRF Token is malformed"),
+            Self::TokenNotIssued => write!(f, "No CSRF Token was issued for this request"),
+            Self::AmbiguousCookie => write!(f, "The CSRF Cookies have conflicting values"),
         }
     }
 }
 
 impl ResponseError for CsrfError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::TokenNotIssued => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
     fn error_response(&self) -> HttpResponse {
-        warn!("Potential CSRF attack: {}", self);
-        HttpResponse::UnprocessableEntity().finish()
+        if *self == Self::TokenNotIssued {
+            error!("{}; is the CSRF middleware installed for this route?", self);
+        } else {
+            warn!("Potential CSRF attack: {}", self);
+        }
+        HttpResponse::new(self.status_code())
     }
 }
 
 impl Error for CsrfError {}
 
+/// Signals a handler can send to the middleware by inserting them into the
+/// response extensions.
+///
+/// ```
+/// use actix_csrf::CsrfAction;
+/// use actix_web::{HttpResponse, Responder};
+///
+/// async fn step_up() -> impl Responder {
+///     let mut res = HttpResponse::Ok().finish();
+///     res.extensions_mut().insert(CsrfAction::Reissue);
+///     res
+/// }
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum CsrfAction {
+    /// Issues a fresh token with the response, replacing any token issued for
+    /// the request. This is useful when the handler changes the security
+    /// context of the session, such as after step-up authentication.
+    Reissue,
+}
+
+/// Errors caused by incompatible [`CsrfMiddleware`] options, as returned by
+/// [`CsrfMiddleware::try_build`].
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum CsrfBuildError {
+    /// The cookie name, or one of the legacy cookie names, is not a valid
+    /// cookie name.
+    InvalidCookieName(String),
+    /// A `__Host-` prefixed cookie requires the `Secure` attribute.
+    HostPrefixRequiresSecure,
+    /// A `__Host-` prefixed cookie must not have a `Domain` attribute.
+    HostPrefixWithDomain,
+    /// A `__Secure-` prefixed cookie requires the `Secure` attribute.
+    SecurePrefixRequiresSecure,
+    /// A cookie with `SameSite=None` requires the `Secure` attribute.
+    SameSiteNoneRequiresSecure,
+    /// The token entropy, in bits, is outside of the supported range.
+    TokenEntropyOutOfRange(usize),
+}
+
+impl Display for CsrfBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidCookieName(name) => write!(f, "{name:?} is not a valid cookie name"),
+            Self::HostPrefixRequiresSecure => {
+                write!(f, "__Host- prefixed cookies must be Secure")
+            }
+            Self::HostPrefixWithDomain => {
+                write!(f, "__Host- prefixed cookies must not have a Domain")
+            }
+            Self::SecurePrefixRequiresSecure => {
+                write!(f, "__Secure- prefixed cookies must be Secure")
+            }
+            Self::SameSiteNoneRequiresSecure => {
+                write!(f, "SameSite=None cookies must be Secure")
+            }
+            Self::TokenEntropyOutOfRange(bits) => write!(
+                f,
+                "token entropy of {bits} bits is outside of {MIN_TOKEN_ENTROPY_BITS}..={MAX_TOKEN_ENTROPY_BITS}"
+            ),
+        }
+    }
+}
+
+impl Error for CsrfBuildError {}
+
 /// CSRF middleware to manage CSRF cookies and tokens.
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct CsrfMiddleware<Rng> {
@@ -215,6 +352,27 @@ impl<Rng: TokenRng + SeedableRng> CsrfMiddleware<Rng> {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Creates a CSRF middleware following Django's conventions, to ease
+    /// migrating clients from Django. Namely, the cookie is named `csrftoken`
+    /// instead of being prefixed, and the following are set:
+    ///
+    /// - `SameSite` is set to `Lax`.
+    /// - `HttpOnly` is _not_ set, so that scripts can read the cookie.
+    ///
+    /// Unlike Django, `Secure` is still set by default. Pair this with
+    /// [`cookie_config`](Self::cookie_config) and
+    /// [`CsrfHeaderConfig::django_compat`](extractor::CsrfHeaderConfig::django_compat)
+    /// to read the `X-CSRFToken` header. Forms may name their token field
+    /// `csrfmiddlewaretoken`, as any field implementing
+    /// [`CsrfGuarded`](extractor::CsrfGuarded) is accepted.
+    #[must_use]
+    pub fn django_compat() -> Self {
+        Self::new()
+            .cookie_name("csrftoken")
+            .same_site(Some(SameSite::Lax))
+            .http_only(false)
+    }
 }
 
 impl<Rng: TokenRng> CsrfMiddleware<Rng> {
@@ -256,10 +414,23 @@ impl<Rng> CsrfMiddleware<Rng> {
         self
     }
 
+    /// Controls whether `HEAD` requests issue the cookie on paths registered
+    /// with [`set_cookie`](Self::set_cookie) for `GET`, so a `HEAD` request
+    /// behaves like the `GET` request it stands for. Enabled by default.
+    #[must_use]
+    pub const fn head_mirrors_get(mut self, enabled: bool) -> Self {
+        self.inner.head_mirrors_get = enabled;
+        self
+    }
+
     /// Sets the cookie name. Consider using [`host_prefixed_cookie_name`][1] or
     /// [`secure_prefixed_cookie_name`][2] to prefix the cookie name with
     /// `__Host-` or `__Secure-` on your behalf, or prefixing it manually.
     ///
+    /// Extractors behind the middleware read the cookie under the same name.
+    /// Cookie names must be valid HTTP tokens, which
+    /// [`try_build`](Self::try_build) checks.
+    ///
     /// [1]: Self::host_prefixed_cookie_name
     /// [2]: Self::secure_prefixed_cookie_name
     #[must_use]
@@ -321,6 +492,126 @@ impl<Rng> CsrfMiddleware<Rng> {
         self
     }
 
+    /// Derives the cookie name from each request, for example to give every
+    /// tenant of a multi-tenant app its own cookie based on the `Host` header.
+    ///
+    /// The derived name is used both when setting the cookie and when the
+    /// [`Csrf`](extractor::Csrf) and [`CsrfCookie`](extractor::CsrfCookie)
+    /// extractors read it, taking precedence over any [`CsrfCookieConfig`].
+    /// No prefix is added on your behalf. If the function returns an invalid
+    /// cookie name, the static cookie name is used instead and a warning is
+    /// logged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use actix_csrf::CsrfMiddleware;
+    /// use rand::rngs::StdRng;
+    ///
+    /// let csrf = CsrfMiddleware::<StdRng>::new().cookie_name_fn(|req| {
+    ///     let host = req.connection_info().host().to_owned();
+    ///     let tenant = host.split('.').next().unwrap_or_default();
+    ///     format!("__Host-{}-Csrf-Token", tenant)
+    /// });
+    /// ```
+    #[must_use]
+    pub fn cookie_name_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> String + 'static,
+    {
+        self.inner.cookie_name_fn = Some(CookieNameFn(Rc::new(f)));
+        self
+    }
+
+    /// Sets a callback that is called whenever a request passes validation by
+    /// the [`Csrf`](extractor::Csrf) extractor, such as to write an audit log
+    /// of state-changing requests.
+    ///
+    /// The callback runs during extraction, before the handler is called, so
+    /// it is also called for requests whose handler later fails.
+    #[must_use]
+    pub fn on_validated<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&HttpRequest) + 'static,
+    {
+        self.inner.on_validated = Some(OnValidated(Rc::new(f)));
+        self
+    }
+
+    /// Sets the backend that reads and writes the CSRF cookie, for deployments
+    /// that store it somewhere other than the `Cookie` and `Set-Cookie`
+    /// headers. Defaults to [`CookieHeaders`].
+    #[must_use]
+    pub fn cookie_backend<B>(mut self, backend: B) -> Self
+    where
+        B: CookieBackend + 'static,
+    {
+        self.inner.cookie_backend = Some(SharedCookieBackend(Rc::new(backend)));
+        self
+    }
+
+    /// Sets the codec that splits tokens into their secret and public parts,
+    /// such as a routing prefix checked against the request. Only the secrets
+    /// are compared during validation. Defaults to [`IdentityCodec`].
+    #[must_use]
+    pub fn token_codec<C>(mut self, codec: C) -> Self
+    where
+        C: TokenCodec + 'static,
+    {
+        self.inner.token_codec = Some(SharedTokenCodec(Rc::new(codec)));
+        self
+    }
+
+    /// Copies `Max-Age` and `Expires` from the named cookie, such as the
+    /// session cookie, when issuing the CSRF cookie, so both expire together.
+    ///
+    /// This is best effort. Browsers don't send cookie attributes back, so the
+    /// attributes can only be copied when the named cookie is set by the same
+    /// response, for example when a login handler starts a session on a
+    /// `set_cookie` route. Otherwise, the CSRF cookie falls back to the
+    /// [`max_age`](Self::max_age), if any, and remains a session cookie
+    /// without one. A named cookie that removes the session, such as on
+    /// logout, is ignored, so that the freshly issued CSRF cookie is kept.
+    #[must_use]
+    pub fn align_expiry_with<T: Into<String>>(mut self, cookie_name: T) -> Self {
+        self.inner.align_expiry_with = Some(cookie_name.into());
+        self
+    }
+
+    /// Sets the `Max-Age` of the CSRF cookie. Defaults to none, which makes it
+    /// a session cookie. Attributes copied through
+    /// [`align_expiry_with`](Self::align_expiry_with) take precedence.
+    #[must_use]
+    pub fn max_age(mut self, max_age: impl Into<Option<cookie::time::Duration>>) -> Self {
+        self.inner.max_age = max_age.into();
+        self
+    }
+
+    /// Rejects requests that carry several CSRF cookies with different values
+    /// with [`CsrfError::AmbiguousCookie`], instead of validating against the
+    /// first one. A sibling subdomain can plant such a cookie to force a known
+    /// token on the user. Disabled by default.
+    #[must_use]
+    pub const fn reject_ambiguous_cookie(mut self, enabled: bool) -> Self {
+        self.inner.reject_ambiguous_cookie = enabled;
+        self
+    }
+
+    /// Signs the CSRF cookie with the provided key, such as the key already
+    /// used for the application's signed session cookies.
+    ///
+    /// Cookies are verified before their token is compared, so a cookie that
+    /// was tampered with, or planted by a sibling subdomain without the key, is
+    /// rejected with [`CsrfError::InvalidToken`]. Tokens handed to the
+    /// application through [`CsrfToken`] are not signed, so clients submit the
+    /// token as usual.
+    #[cfg(feature = "signed")]
+    #[must_use]
+    pub fn signing_key(mut self, key: Key) -> Self {
+        self.inner.signing_key = Some(SigningKey(Rc::new(key)));
+        self
+    }
+
     /// Sets the `SameSite` attribute on the cookie.
     #[must_use]
     pub const fn same_site(mut self, same_site: Option<SameSite>) -> Self {
@@ -342,6 +633,32 @@ impl<Rng> CsrfMiddleware<Rng> {
         self
     }
 
+    /// Makes the middleware respond with 500 Internal Server Error instead of
+    /// issuing a `Secure` cookie over plain HTTP, which browsers would ignore.
+    ///
+    /// By default, a warning is logged at most once a minute instead. This is
+    /// meant for staging environments, to catch a missing HTTPS setup early.
+    /// The scheme is read from the connection info, so it respects the
+    /// `Forwarded` and `X-Forwarded-Proto` headers set by proxies.
+    #[must_use]
+    pub const fn require_secure_transport(mut self, enabled: bool) -> Self {
+        self.inner.require_secure_transport = enabled;
+        self
+    }
+
+    /// Skips issuing the token cookie with redirects whose `Location` points
+    /// to a host the cookie would not be sent to, to avoid churning tokens.
+    ///
+    /// Redirects to relative locations and to the request's host, or to hosts
+    /// within the configured [`domain`](Self::domain), still receive the
+    /// cookie. Redirects whose `Location` can't be parsed are skipped as well.
+    /// Disabled by default.
+    #[must_use]
+    pub const fn skip_cookie_on_foreign_redirects(mut self, enabled: bool) -> Self {
+        self.inner.skip_cookie_on_foreign_redirects = enabled;
+        self
+    }
+
     /// Sets the domain of the cookie.
     ///
     /// This is incompatible with `__Host-` prefixed cookies. If the cookie is
@@ -358,9 +675,98 @@ impl<Rng> CsrfMiddleware<Rng> {
         self
     }
 
+    /// Sets cookie names that were previously used for the CSRF cookie, to
+    /// migrate clients after renaming it.
+    ///
+    /// If a request lacks the current cookie, extractors fall back to the first
+    /// legacy cookie present, in the provided order. The response then reissues
+    /// that token under the current name and removes the legacy cookies. Once
+    /// clients have been migrated, the legacy names should be removed.
+    #[must_use]
+    pub fn legacy_cookie_names<S: AsRef<str>>(mut self, names: &[S]) -> Self {
+        self.inner.legacy_cookie_names =
+            names.iter().map(|name| name.as_ref().to_owned()).collect();
+        self
+    }
+
+    /// Sets the entropy of generated tokens, in bits. Defaults to 256 bits.
+    ///
+    /// Tokens are base64 encoded, so each character carries 6 bits of entropy.
+    /// The entropy is rounded up to whole bytes, so requesting 128 bits yields
+    /// a 22 character token.
+    ///
+    /// The entropy must be between 128 and 2048 bits; other values are
+    /// rejected by [`try_build`](Self::try_build) and when the middleware is
+    /// installed.
+    #[must_use]
+    pub const fn token_entropy_bits(mut self, bits: usize) -> Self {
+        self.inner.token_entropy_bits = bits;
+        self
+    }
+
+    /// Generates tokens with [`rand::thread_rng`] instead of the middleware's
+    /// own RNG. The thread-local RNG is a CSPRNG that reseeds itself
+    /// periodically, and is shared with the rest of the thread instead of
+    /// being seeded for each middleware.
+    #[must_use]
+    pub const fn use_thread_rng(mut self, enabled: bool) -> Self {
+        self.inner.use_thread_rng = enabled;
+        self
+    }
+
+    /// Makes the middleware always issue the provided token instead of a
+    /// randomly generated one.
+    ///
+    /// This is only meant for integration tests and development environments,
+    /// where external test clients may need to hardcode a known token. Unlike
+    /// disabling the middleware, tokens are still validated as usual. A fixed
+    /// token offers no protection whatsoever, so this setting is ignored with a
+    /// warning in builds without debug assertions.
+    #[must_use]
+    pub fn fixed_token<T: Into<String>>(mut self, token: T) -> Self {
+        if cfg!(debug_assertions) {
+            warn!("CSRF tokens are fixed; this must never be used in production");
+            self.inner.fixed_token = Some(token.into());
+        } else {
+            warn!("Ignoring fixed CSRF token in a release build");
+        }
+        self
+    }
+
+    /// Checks that the configured options are compatible with each other,
+    /// returning the middleware if they are.
+    ///
+    /// Builder methods never fail, so incompatible options, such as a
+    /// `__Host-` prefixed cookie without the `Secure` attribute, are only
+    /// detected here. The same checks run when the middleware is installed,
+    /// which then fails to start with an error logged.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first incompatibility found.
+    pub fn try_build(self) -> Result<Self, CsrfBuildError> {
+        self.inner.validate().map(|()| self)
+    }
+
+    /// Returns a short identifier of the crate version and the middleware's
+    /// non-secret configuration, such as to include in bug reports.
+    ///
+    /// The fingerprint changes whenever an option that affects behavior
+    /// changes, and is identical across workers and builds of the same
+    /// configuration. Callbacks and the fixed token or signing key only
+    /// contribute whether they are set.
+    #[must_use]
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "{}-{:016x}",
+            env!("CARGO_PKG_VERSION"),
+            self.inner.fingerprint()
+        )
+    }
+
     /// Produces an CSRF cookie config determined from the current middleware
-    /// state. Note that this is **not** needed if you are using default cookie
-    /// names.
+    /// state. Note that this is **not** needed for requests handled by the
+    /// middleware, whose extractors read the cookie name the middleware used.
     #[must_use]
     pub fn cookie_config(&self) -> CsrfCookieConfig {
         CsrfCookieConfig::new((*self.inner.cookie_name).clone())
@@ -378,8 +784,8 @@ impl<Rng: TokenRng + SeedableRng> Default for CsrfMiddleware<Rng> {
 
 impl<S, Rng> Transform<S, ServiceRequest> for CsrfMiddleware<Rng>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse>,
-    Rng: TokenRng + Clone,
+    S: Service<ServiceRequest, Response = ServiceResponse> + 'static,
+    Rng: TokenRng + Clone + 'static,
 {
     type Response = ServiceResponse;
     type Error = S::Error;
@@ -388,31 +794,77 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
+        if let Err(e) = self.inner.validate() {
+            error!("Invalid CSRF middleware configuration: {e}");
+            return future::ready(Err(()));
+        }
+
+        debug!(
+            "Starting CSRF middleware with configuration {}",
+            self.fingerprint()
+        );
+
         future::ready(Ok(CsrfMiddlewareImpl {
-            service,
-            inner: self.inner.clone(),
+            service: Rc::new(service),
+            inner: Rc::new(self.inner.clone()),
+            insecure_transport_warning: WarningLimiter::default(),
         }))
     }
 }
 
 #[doc(hidden)]
 pub struct CsrfMiddlewareImpl<S, Rng> {
-    service: S,
-    inner: Inner<Rng>,
+    // Reference counted so that it can be moved into the response future,
+    // which outlives the borrow of `self` in `Service::call`.
+    service: Rc<S>,
+    inner: Rc<Inner<Rng>>,
+    insecure_transport_warning: WarningLimiter,
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
+#[allow(clippy::struct_excessive_bools)] // independent builder options
 struct Inner<Rng> {
     /// To generate the token
     rng: RefCell<Rng>,
     cookie_name: Rc<String>,
+    /// Previous cookie names, accepted when `cookie_name` is absent
+    legacy_cookie_names: Vec<String>,
+    /// Overrides `cookie_name` on a per-request basis
+    cookie_name_fn: Option<CookieNameFn>,
+    /// Called when a request passes validation
+    on_validated: Option<OnValidated>,
+    /// Reads and writes the cookie instead of the cookie headers
+    cookie_backend: Option<SharedCookieBackend>,
+    /// Encodes issued tokens and decodes received ones
+    token_codec: Option<SharedTokenCodec>,
+    /// Signs issued cookies and verifies received ones
+    #[cfg(feature = "signed")]
+    signing_key: Option<SigningKey>,
     http_only: bool,
     same_site: Option<SameSite>,
     secure: bool,
     domain: Option<String>,
+    /// Entropy of generated tokens, in bits
+    token_entropy_bits: usize,
+    /// If true, generates tokens with the thread-local RNG instead of `rng`
+    use_thread_rng: bool,
+    /// Issued instead of a generated token, for testing only
+    fixed_token: Option<String>,
 
     /// If false, will not check at all for CSRF tokens
     csrf_enabled: bool,
+    /// If true, refuses to issue `Secure` cookies over plain HTTP
+    require_secure_transport: bool,
+    /// If true, skips the cookie on redirects to hosts it isn't sent to
+    skip_cookie_on_foreign_redirects: bool,
+    /// If true, `HEAD` requests issue the cookie where `GET` requests would
+    head_mirrors_get: bool,
+    /// If true, rejects requests with conflicting CSRF cookies
+    reject_ambiguous_cookie: bool,
+    /// Name of the session cookie whose expiry the CSRF cookie copies
+    align_expiry_with: Option<String>,
+    /// Max-Age of the CSRF cookie, unless copied from the session cookie
+    max_age: Option<cookie::time::Duration>,
     set_cookie: HashSet<(Method, String)>,
 }
 
@@ -422,208 +874,622 @@ impl<Rng: TokenRng + SeedableRng> Default for Inner<Rng> {
     }
 }
 
+impl<Rng> Inner<Rng> {
+    /// Checks the cookie options against the constraints browsers enforce.
+    fn validate(&self) -> Result<(), CsrfBuildError> {
+        let names = std::iter::once(&*self.cookie_name).chain(&self.legacy_cookie_names);
+        if let Some(name) = names.into_iter().find(|name| !is_valid_cookie_name(name)) {
+            return Err(CsrfBuildError::InvalidCookieName(name.clone()));
+        }
+
+        if self.cookie_name.starts_with(host_prefix!()) {
+            if !self.secure {
+                return Err(CsrfBuildError::HostPrefixRequiresSecure);
+            }
+            if self.domain.is_some() {
+                return Err(CsrfBuildError::HostPrefixWithDomain);
+            }
+        }
+
+        if self.cookie_name.starts_with(secure_prefix!()) && !self.secure {
+            return Err(CsrfBuildError::SecurePrefixRequiresSecure);
+        }
+
+        if self.same_site == Some(SameSite::None) && !self.secure {
+            return Err(CsrfBuildError::SameSiteNoneRequiresSecure);
+        }
+
+        if !(MIN_TOKEN_ENTROPY_BITS..=MAX_TOKEN_ENTROPY_BITS).contains(&self.token_entropy_bits) {
+            return Err(CsrfBuildError::TokenEntropyOutOfRange(
+                self.token_entropy_bits,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Lists the routes that issue the cookie as `METHOD path`, sorted.
+    fn set_cookie_routes(&self) -> Vec<String> {
+        let mut routes: Vec<_> = self
+            .set_cookie
+            .iter()
+            .map(|(method, path)| format!("{method} {path}"))
+            .collect();
+        routes.sort();
+        routes
+    }
+
+    /// Hashes the non-secret configuration with FNV-1a, which unlike the
+    /// standard library's hasher is stable across builds.
+    fn fingerprint(&self) -> u64 {
+        #[cfg(feature = "signed")]
+        let signed = self.signing_key.is_some();
+        #[cfg(not(feature = "signed"))]
+        let signed = false;
+
+        let config = format!(
+            "{:?}",
+            (
+                (&self.cookie_name, &self.legacy_cookie_names),
+                self.set_cookie_routes(),
+                (self.http_only, self.same_site, self.secure, &self.domain),
+                (self.token_entropy_bits, self.use_thread_rng),
+                (self.csrf_enabled, self.require_secure_transport),
+                (self.skip_cookie_on_foreign_redirects, self.head_mirrors_get),
+                (self.reject_ambiguous_cookie, &self.align_expiry_with),
+                self.max_age,
+                (self.cookie_name_fn.is_some(), self.on_validated.is_some()),
+                (self.cookie_backend.is_some(), self.token_codec.is_some()),
+                (self.fixed_token.is_some(), signed),
+                (cfg!(feature = "metrics"), cfg!(feature = "signed")),
+            )
+        );
+
+        config.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
 impl<Rng: TokenRng> Inner<Rng> {
     fn with_rng(rng: Rng) -> Self {
         Self {
             rng: RefCell::new(rng),
             cookie_name: Rc::new(DEFAULT_CSRF_COOKIE_NAME.to_owned()),
+            legacy_cookie_names: Vec::new(),
+            cookie_name_fn: None,
+            on_validated: None,
+            cookie_backend: None,
+            token_codec: None,
+            #[cfg(feature = "signed")]
+            signing_key: None,
             csrf_enabled: true,
+            require_secure_transport: false,
+            skip_cookie_on_foreign_redirects: false,
+            head_mirrors_get: true,
+            reject_ambiguous_cookie: false,
+            align_expiry_with: None,
+            max_age: None,
             http_only: true,
             same_site: Some(SameSite::Strict),
             secure: true,
             domain: None,
+            token_entropy_bits: DEFAULT_TOKEN_ENTROPY_BITS,
+            use_thread_rng: false,
+            fixed_token: None,
             set_cookie: HashSet::new(),
         }
     }
 
-    fn contains(&self, req: &ServiceRequest) -> bool {
-        req.match_pattern().map_or_else(
+    fn generate_token(&self) -> Result<String, rand::Error> {
+        self.fixed_token.as_ref().map_or_else(
             || {
-                self.set_cookie
-                    .contains(&(req.method().clone(), req.path().to_string()))
+                if self.use_thread_rng {
+                    rand::thread_rng().generate_token_with_entropy(self.token_entropy_bits)
+                } else {
+                    self.rng
+                        .borrow_mut()
+                        .generate_token_with_entropy(self.token_entropy_bits)
+                }
             },
-            |p| self.set_cookie.contains(&(req.method().clone(), p)),
+            |token| Ok(token.clone()),
         )
     }
-}
 
-impl<S, Rng> Service<ServiceRequest> for CsrfMiddlewareImpl<S, Rng>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse>,
-    Rng: TokenRng,
-{
-    type Response = ServiceResponse;
-    type Error = S::Error;
-    type Future = CsrfMiddlewareImplFuture<S>;
+    /// Builds the cookie carrying the token for the response, signed if a
+    /// signing key was configured, and counts it as issued.
+    fn token_cookie(&self, name: &str, token: String, res: &ServiceResponse) -> Cookie<'static> {
+        counters::issued();
+        let mut cookie = self.build_cookie(name, token);
+        if let Some(session_cookie) = self.align_expiry_with.as_ref().and_then(|session_name| {
+            res.response()
+                .cookies()
+                .find(|v| v.name() == session_name && !is_removal(v))
+        }) {
+            cookie.set_max_age(session_cookie.max_age());
+            if let Some(expires) = session_cookie.expires() {
+                cookie.set_expires(expires);
+            }
+        }
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        let cookie = if self.inner.csrf_enabled && self.inner.contains(&req) {
-            let token =
-                match self.inner.rng.borrow_mut().generate_token() {
-                    Ok(token) => token,
-                    Err(e) => {
-                        error!("Failed to generate CSRF token, aborting request");
-                        return CsrfMiddlewareImplFuture::CsrfError(req.error_response(
-                            InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR),
-                        ));
-                    }
-                };
+        self.sign_cookie(cookie.into_owned())
+    }
 
-            let cookie = {
-                let mut cookie_builder =
-                    Cookie::build(self.inner.cookie_name.as_ref(), token.clone())
-                        .http_only(self.inner.http_only)
-                        .secure(self.inner.secure)
-                        .path("/");
+    /// Generates a token for the request, encoded by the token codec if one
+    /// was configured.
+    fn issue_token(&self, req: &HttpRequest) -> Result<String, rand::Error> {
+        let secret = self.generate_token()?;
+        Ok(match &self.token_codec {
+            Some(codec) => codec.0.encode(req, secret),
+            None => secret,
+        })
+    }
 
-                if let Some(same_site) = self.inner.same_site {
-                    cookie_builder = cookie_builder.same_site(same_site);
-                }
+    /// Returns the configured cookie backend, or the cookie headers.
+    fn cookie_backend(&self) -> &dyn CookieBackend {
+        self.cookie_backend
+            .as_ref()
+            .map_or(&CookieHeaders, |backend| &*backend.0)
+    }
 
-                if let Some(domain) = &self.inner.domain {
-                    cookie_builder = cookie_builder.domain(domain);
-                }
+    /// Signs the cookie if a signing key was configured.
+    #[cfg_attr(not(feature = "signed"), allow(clippy::unused_self))]
+    fn sign_cookie(&self, cookie: Cookie<'static>) -> Cookie<'static> {
+        #[cfg(feature = "signed")]
+        if let Some(key) = &self.signing_key {
+            return key.sign(cookie);
+        }
+
+        cookie
+    }
+
+    /// Verifies the cookie if a signing key was configured, returning it with
+    /// its signature stripped.
+    #[cfg_attr(
+        not(feature = "signed"),
+        allow(clippy::unused_self, clippy::unnecessary_wraps)
+    )]
+    fn verify_cookie(&self, cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+        #[cfg(feature = "signed")]
+        if let Some(key) = &self.signing_key {
+            return key.verify(cookie);
+        }
+
+        Some(cookie)
+    }
+
+    /// Builds a CSRF cookie with the configured attributes.
+    fn build_cookie<'c>(&self, name: &'c str, value: String) -> Cookie<'c> {
+        let mut cookie_builder = Cookie::build(name, value)
+            .http_only(self.http_only)
+            .secure(self.secure)
+            .path("/");
 
-                cookie_builder.finish()
-            };
+        if let Some(same_site) = self.same_site {
+            cookie_builder = cookie_builder.same_site(same_site);
+        }
+
+        if let Some(domain) = &self.domain {
+            cookie_builder = cookie_builder.domain(domain.clone());
+        }
 
-            let csrf_token = CsrfToken(token);
-            req.extensions_mut().insert(csrf_token);
+        if let Some(max_age) = self.max_age {
+            cookie_builder = cookie_builder.max_age(max_age);
+        }
 
-            // The characters allowed in a cookie should be a strict subset
-            // of the characters allowed in a header, so this should never
-            // fail.
-            let header = HeaderValue::from_str(&cookie.to_string())
-                .expect("cookie to be a valid header value");
+        cookie_builder.finish()
+    }
 
-            Some(header)
+    /// Resolves the cookie name for this request if a cookie name function was
+    /// configured, falling back to the static name if it produces an invalid
+    /// name.
+    fn resolve_cookie_name(&self, req: &ServiceRequest) -> Option<String> {
+        let name = (self.cookie_name_fn.as_ref()?.0)(req);
+        if is_valid_cookie_name(&name) {
+            Some(name)
         } else {
+            warn!(
+                "Derived CSRF cookie name {:?} is invalid, using {:?} instead",
+                name, self.cookie_name
+            );
             None
+        }
+    }
+
+    /// Stores the request state that extractors read in the request extensions.
+    fn insert_extensions(&self, req: &ServiceRequest, cookie_name: &str) {
+        let mut extensions = req.extensions_mut();
+        extensions.insert(ResolvedCookieName(cookie_name.to_owned()));
+        if let Some(on_validated) = &self.on_validated {
+            extensions.insert(on_validated.clone());
+        }
+        if let Some(cookie_backend) = &self.cookie_backend {
+            extensions.insert(cookie_backend.clone());
+        }
+        if let Some(token_codec) = &self.token_codec {
+            extensions.insert(token_codec.clone());
+        }
+        if self.reject_ambiguous_cookie {
+            extensions.insert(RejectAmbiguousCookie);
+        }
+        if bypass::is_disabled() {
+            extensions.insert(ValidationDisabled);
+        }
+
+        #[cfg(feature = "signed")]
+        if let Some(signing_key) = &self.signing_key {
+            extensions.insert(signing_key.clone());
+        }
+    }
+
+    fn contains(&self, req: &ServiceRequest) -> bool {
+        let path = req
+            .match_pattern()
+            .unwrap_or_else(|| req.path().to_string());
+        let mirrors_get = self.head_mirrors_get && req.method() == Method::HEAD;
+        let method = if mirrors_get {
+            Method::GET
+        } else {
+            req.method().clone()
         };
 
-        CsrfMiddlewareImplFuture::Passthrough(Passthrough {
-            cookie,
-            service: Box::pin(self.service.call(req)),
-        })
+        self.set_cookie.contains(&(method, path.clone()))
+            || mirrors_get && self.set_cookie.contains(&(Method::HEAD, path))
     }
+}
 
-    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(ctx)
+/// A function that derives the cookie name from a request.
+#[derive(Clone)]
+struct CookieNameFn(Rc<dyn Fn(&ServiceRequest) -> String>);
+
+impl PartialEq for CookieNameFn {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::as_ptr(&self.0).cast::<()>() == Rc::as_ptr(&other.0).cast::<()>()
     }
 }
 
-#[doc(hidden)]
-#[derive(Debug)]
-pub enum CsrfMiddlewareImplFuture<S: Service<ServiceRequest>> {
-    /// A CSRF issue was detected.
-    CsrfError(ServiceResponse),
-    /// No CSRF issue was detected, so we pass the request through.
-    Passthrough(Passthrough<S::Future>),
+impl Eq for CookieNameFn {}
+
+impl Debug for CookieNameFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CookieNameFn")
+    }
 }
 
-impl<S> Future for CsrfMiddlewareImplFuture<S>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse>,
-{
-    type Output = Result<ServiceResponse, S::Error>;
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.get_mut() {
-            Self::CsrfError(error) => {
-                // TODO: Find a way to not have to clone.
-                let req = error.request().clone();
-                let mut new_error = ServiceResponse::new(req, HttpResponse::NoContent().finish());
-                std::mem::swap(&mut new_error, error);
-                Poll::Ready(Ok(new_error))
-            }
-            Self::Passthrough(inner) => match inner.service.as_mut().poll(cx) {
-                Poll::Ready(Ok(mut res)) => {
-                    if let Some(ref cookie) = inner.cookie {
-                        res.response_mut()
-                            .headers_mut()
-                            // TODO: Find a way to not have to clone.
-                            .insert(header::SET_COOKIE, cookie.clone());
-                    }
+/// A callback for requests that passed validation. It is stored in the request
+/// extensions so that the [`Csrf`](extractor::Csrf) extractor can call it.
+#[derive(Clone)]
+pub(crate) struct OnValidated(pub(crate) Rc<dyn Fn(&HttpRequest)>);
 
-                    Poll::Ready(Ok(res))
-                }
-                other => other,
-            },
-        }
+impl PartialEq for OnValidated {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::as_ptr(&self.0).cast::<()>() == Rc::as_ptr(&other.0).cast::<()>()
     }
 }
 
-#[doc(hidden)]
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-pub struct Passthrough<Fut> {
-    cookie: Option<HeaderValue>,
-    service: Pin<Box<Fut>>,
+impl Eq for OnValidated {}
+
+impl Debug for OnValidated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnValidated")
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::extractor::{Csrf, CsrfHeader};
+/// Marks a request received while validation was turned off by
+/// [`with_csrf_disabled`](test::with_csrf_disabled), so that extractors polled
+/// outside of it still skip validation.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ValidationDisabled;
 
-    use super::*;
+/// How often to warn about `Secure` cookies issued over plain HTTP.
+const INSECURE_TRANSPORT_WARNING_INTERVAL: Duration = Duration::from_secs(60);
 
-    use actix_web::http::StatusCode;
-    use actix_web::test::{self, TestRequest};
-    use actix_web::{post, web, App, HttpResponse, Responder};
-    use rand::rngs::StdRng;
+/// Rate limits a repeated warning to once per
+/// [`INSECURE_TRANSPORT_WARNING_INTERVAL`].
+#[derive(Default, Debug)]
+struct WarningLimiter {
+    last_warning: Cell<Option<Instant>>,
+}
 
-    fn get_token_from_resp(resp: &ServiceResponse) -> String {
-        let cookie = get_cookie_from_resp(resp);
-        // should be something like "Csrf-Token=NHMWzEq7nAFZR56jnanhFv6WJdeEAyhy; Path=/"
-        let token_header = cookie.split('=');
-        let token = token_header.skip(1).take(1).collect::<Vec<_>>()[0];
-        let token = token.split(';').next().expect("split to work");
-        String::from(token)
-    }
+impl WarningLimiter {
+    /// Checks if the warning should be emitted now, recording it if so.
+    fn check(&self, now: Instant) -> bool {
+        if let Some(last_warning) = self.last_warning.get() {
+            if now.duration_since(last_warning) < INSECURE_TRANSPORT_WARNING_INTERVAL {
+                return false;
+            }
+        }
 
-    fn get_cookie_from_resp(resp: &ServiceResponse) -> String {
-        let cookie_header: Vec<_> = resp
-            .headers()
-            .iter()
-            .filter(|(header_name, _)| header_name.as_str() == "set-cookie")
-            .map(|(_, value)| value.to_str().expect("header to be valid string"))
-            .map(|v| v.split(';').next().expect("split to work"))
-            .collect();
-        assert_eq!(1, cookie_header.len());
-        String::from(*cookie_header.get(0).expect("header to have cookie"))
+        self.last_warning.set(Some(now));
+        true
     }
+}
 
-    fn get_cookie_domain_from_resp(resp: &ServiceResponse) -> String {
-        let cookie_header: Vec<_> = resp
-            .headers()
-            .iter()
-            .filter(|(header_name, _)| header_name.as_str() == "set-cookie")
-            .map(|(_, value)| value.to_str().expect("header to be valid string"))
-            .flat_map(|v| v.split(';'))
-            .collect();
-        String::from(
-            cookie_header
-                .into_iter()
-                .find_map(|s| s.trim().strip_prefix("Domain="))
-                .expect("header to have cookie"),
-        )
+/// The cookie name used for the current request, stored in the request
+/// extensions so that extractors read the same cookie the middleware sets.
+#[derive(Clone, Debug)]
+pub(crate) struct ResolvedCookieName(pub(crate) String);
+
+/// Marks that extractors should reject conflicting CSRF cookies, stored in the
+/// request extensions.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RejectAmbiguousCookie;
+
+/// The value of the legacy cookie used in place of the missing current cookie,
+/// stored in the request extensions so that extractors can fall back to it.
+#[derive(Clone, Debug)]
+pub(crate) struct LegacyCookie(pub(crate) String);
+
+/// Checks if the browser sends the cookie to the redirect target. Relative
+/// locations always receive it, while absolute ones must point to the request's
+/// host, or a host within the cookie's domain if set. Unparsable locations are
+/// considered out of scope.
+fn is_in_cookie_scope(location: &HeaderValue, request_host: &str, domain: Option<&str>) -> bool {
+    let location = match location.to_str() {
+        Ok(location) => location,
+        Err(_) => return false,
+    };
+
+    if !location.starts_with("//") && !location.contains("://") {
+        return true;
     }
 
-    #[tokio::test]
-    async fn attaches_token() {
-        let mut srv = test::init_service(
-            App::new()
-                .wrap(CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/"))
-                .service(web::resource("/").to(|| HttpResponse::Ok())),
+    let host = match location
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.host().map(str::to_owned))
+    {
+        Some(host) => host,
+        None => return false,
+    };
+
+    domain
+        .map(|domain| domain.trim_start_matches('.'))
+        .map_or_else(
+            || {
+                request_host
+                    .parse::<Authority>()
+                    .map_or(false, |authority| {
+                        host.eq_ignore_ascii_case(authority.host())
+                    })
+            },
+            |domain| {
+                host.eq_ignore_ascii_case(domain)
+                    || host
+                        .to_ascii_lowercase()
+                        .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+            },
         )
-        .await;
-        let resp = test::call_service(&mut srv, TestRequest::with_uri("/").to_request()).await;
-        assert_eq!(resp.status(), StatusCode::OK);
+}
 
-        // Cookie should be in the response.
-        assert!(get_cookie_from_resp(&resp).contains(DEFAULT_CSRF_COOKIE_NAME));
-    }
+/// Checks if the response redirects to a location outside the cookie's scope.
+fn is_foreign_redirect<B>(
+    res: &ServiceResponse<B>,
+    request_host: &str,
+    domain: Option<&str>,
+) -> bool {
+    res.status().is_redirection()
+        && res
+            .headers()
+            .get(header::LOCATION)
+            .map_or(false, |location| {
+                !is_in_cookie_scope(location, request_host, domain)
+            })
+}
 
-    #[tokio::test]
+/// Checks if the cookie removes a cookie instead of setting it, by expiring it
+/// immediately or in the past.
+fn is_removal(cookie: &Cookie) -> bool {
+    cookie
+        .max_age()
+        .map_or(false, |age| age <= cookie::time::Duration::ZERO)
+        || cookie.expires_datetime().map_or(false, |expires| {
+            expires <= cookie::time::OffsetDateTime::now_utc()
+        })
+}
+
+/// Checks if the name is a valid cookie name, which must be a non-empty token
+/// as defined in RFC 6265.
+fn is_valid_cookie_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+}
+
+impl<S, Rng> Service<ServiceRequest> for CsrfMiddlewareImpl<S, Rng>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse> + 'static,
+    Rng: TokenRng + 'static,
+{
+    type Response = ServiceResponse;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let cookie_name = self
+            .inner
+            .resolve_cookie_name(&req)
+            .unwrap_or_else(|| self.inner.cookie_name.as_str().to_owned());
+        self.inner.insert_extensions(&req, &cookie_name);
+
+        // Clients may still hold the cookie under a previous name. The first
+        // legacy cookie present stands in for the missing current cookie, and
+        // all of them are replaced by the current cookie in the response.
+        let backend = self.inner.cookie_backend();
+        let mut legacy_token = None;
+        let mut cookies = Vec::new();
+        if self.inner.csrf_enabled
+            && !self.inner.legacy_cookie_names.is_empty()
+            && backend.read(req.request(), &cookie_name).is_none()
+        {
+            for name in &self.inner.legacy_cookie_names {
+                if let Some(value) = backend.read(req.request(), name) {
+                    if legacy_token.is_none() {
+                        legacy_token = self
+                            .inner
+                            .verify_cookie(Cookie::new(name.clone(), value))
+                            .map(|cookie| cookie.value().to_owned());
+                    }
+                    let mut removal = self.inner.build_cookie(name, String::new());
+                    removal.make_removal();
+                    cookies.push(removal.into_owned());
+                }
+            }
+        }
+
+        let token = if self.inner.csrf_enabled && self.inner.contains(&req) {
+            match self.inner.issue_token(req.request()) {
+                Ok(token) => Some(token),
+                Err(e) => {
+                    error!("Failed to generate CSRF token, aborting request");
+                    let res = req
+                        .error_response(InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR));
+                    return Box::pin(future::ready(Ok(res)));
+                }
+            }
+        } else {
+            legacy_token.clone()
+        };
+
+        if let Some(legacy_token) = legacy_token {
+            req.extensions_mut().insert(LegacyCookie(legacy_token));
+        }
+
+        let mut issued_token = None;
+        if let Some(token) = token {
+            // Browsers silently drop `Secure` cookies set over plain HTTP, so
+            // every protected request would then fail with a missing cookie.
+            if self.inner.secure && req.connection_info().scheme() == "http" {
+                counters::insecure_transport();
+                if self.inner.require_secure_transport {
+                    error!("Refusing to issue a Secure CSRF cookie over plain HTTP");
+                    let res = req.error_response(InternalError::new(
+                        "Secure CSRF cookie requested over plain HTTP",
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    ));
+                    return Box::pin(future::ready(Ok(res)));
+                }
+
+                if self.insecure_transport_warning.check(Instant::now()) {
+                    warn!(
+                        "Issuing a Secure CSRF cookie over plain HTTP, which browsers will ignore. \
+                        Serve the app over HTTPS, forward the scheme from your proxy, or disable \
+                        the Secure attribute for local development."
+                    );
+                }
+            }
+
+            issued_token = Some(token.clone());
+            req.extensions_mut().insert(CsrfToken(token));
+        }
+
+        let request_host = self
+            .inner
+            .skip_cookie_on_foreign_redirects
+            .then(|| req.connection_info().host().to_owned());
+
+        let service = Rc::clone(&self.service);
+        let inner = Rc::clone(&self.inner);
+        Box::pin(async move {
+            let mut res = service.call(req).await?;
+
+            let reissue =
+                res.response().extensions().get::<CsrfAction>() == Some(&CsrfAction::Reissue);
+            if inner.csrf_enabled && reissue {
+                if let Ok(token) = inner.issue_token(res.request()) {
+                    issued_token = Some(token);
+                } else {
+                    error!("Failed to generate CSRF token, keeping the current token");
+                }
+            }
+
+            let domain = inner.domain.as_deref();
+            if request_host.map_or(false, |host| is_foreign_redirect(&res, &host, domain)) {
+                debug!("Not issuing the CSRF cookie with a redirect to another host");
+                issued_token = None;
+            }
+
+            let token_cookie =
+                issued_token.map(|token| inner.token_cookie(&cookie_name, token, &res));
+            let backend = inner.cookie_backend();
+            let headers = res.response_mut().headers_mut();
+            for cookie in cookies.into_iter().chain(token_cookie) {
+                backend.write(headers, &cookie);
+            }
+
+            Ok(res)
+        })
+    }
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::extractor::{Csrf, CsrfCookie, CsrfGuarded, CsrfHeader, MAX_TOKEN_LENGTH};
+    use crate::test::{app_with_csrf, BrowserClient};
+
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{post, web, App, FromRequest, HttpResponse, Responder};
+    use rand::rngs::StdRng;
+    use tracing_test::traced_test;
+
+    /// Parses the single `Set-Cookie` header of the response.
+    fn get_cookie_from_resp(resp: &ServiceResponse) -> Cookie<'static> {
+        let mut cookie_headers = resp.headers().get_all(header::SET_COOKIE);
+        let cookie_header = cookie_headers.next().expect("header to have cookie");
+        assert!(cookie_headers.next().is_none());
+        let cookie_header = cookie_header.to_str().expect("header to be valid string");
+        Cookie::parse(cookie_header)
+            .expect("header to be a valid cookie")
+            .into_owned()
+    }
+
+    #[tokio::test]
+    async fn attaches_token() {
+        let mut srv = test::init_service(
+            App::new()
+                .wrap(CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/"))
+                .service(web::resource("/").to(|| HttpResponse::Ok())),
+        )
+        .await;
+        let resp = test::call_service(&mut srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Cookie should be in the response.
+        assert_eq!(get_cookie_from_resp(&resp).name(), DEFAULT_CSRF_COOKIE_NAME);
+    }
+
+    #[tokio::test]
+    async fn cookie_attributes_are_set() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/"))
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let cookie = get_cookie_from_resp(&resp);
+        assert_eq!(cookie.name(), DEFAULT_CSRF_COOKIE_NAME);
+        assert!(!cookie.value().is_empty());
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.domain(), None);
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+    }
+
+    #[tokio::test]
     async fn post_request_rejected_without_header() {
         #[post("/")]
         async fn test_route(_: Csrf<CsrfHeader>) -> impl Responder {
@@ -644,31 +1510,31 @@ mod tests {
     /// Will use double submit method.
     #[tokio::test]
     async fn double_submit_correct_token() {
-        let mut srv = test::init_service(
-            App::new()
-                .wrap(CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/"))
-                .service(
-                    web::resource("/")
-                        .route(web::get().to(|| HttpResponse::Ok()))
-                        .route(web::post().to(|| HttpResponse::Ok())),
-                ),
-        )
+        let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/");
+        let srv = app_with_csrf(csrf, |cfg| {
+            cfg.service(
+                web::resource("/")
+                    .route(web::get().to(|| HttpResponse::Ok()))
+                    .route(web::post().to(|| HttpResponse::Ok())),
+            );
+        })
         .await;
+        let mut client = BrowserClient::new();
 
         // First, let's get the token as a client.
-        let resp = test::call_service(&mut srv, TestRequest::with_uri("/").to_request()).await;
-
-        let token = get_token_from_resp(&resp);
-        let cookie = get_cookie_from_resp(&resp);
+        client.call(&srv, TestRequest::with_uri("/")).await;
+        let token = client
+            .cookie(DEFAULT_CSRF_COOKIE_NAME)
+            .unwrap()
+            .value()
+            .to_owned();
 
         // Now we can do another request to a protected endpoint.
         let req = TestRequest::post()
             .uri("/")
-            .insert_header(("Cookie", cookie))
-            .insert_header((DEFAULT_CSRF_TOKEN_NAME, token))
-            .to_request();
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token));
 
-        let resp = test::call_service(&mut srv, req).await;
+        let resp = client.call(&srv, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
     }
 
@@ -687,36 +1553,975 @@ mod tests {
         let resp = test::call_service(&mut srv, TestRequest::with_uri("/").to_request()).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
-        assert_eq!(get_cookie_domain_from_resp(&resp), "example.com");
+        assert_eq!(get_cookie_from_resp(&resp).domain(), Some("example.com"));
     }
 
     #[tokio::test]
-    async fn path_info_is_set() {
-        let mut srv = test::init_service(
+    #[traced_test]
+    async fn invalid_domain_skips_cookie() {
+        let srv = test::init_service(
             App::new()
-                .wrap(CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/{id}"))
-                .service(
-                    web::resource("/{id}")
-                        .route(web::get().to(|| HttpResponse::Ok()))
-                        .route(web::post().to(|| HttpResponse::Ok())),
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/")
+                        .domain("example.com\n"),
+                )
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(header::SET_COOKIE).is_none());
+        assert!(logs_contain("is not a valid header value"));
+    }
+
+    #[tokio::test]
+    async fn fixed_token_is_issued_and_validated() {
+        let csrf = CsrfMiddleware::<StdRng>::new()
+            .set_cookie(Method::GET, "/")
+            .fixed_token("fixed-token");
+        let srv = app_with_csrf(csrf, |cfg| {
+            cfg.service(
+                web::resource("/")
+                    .route(web::get().to(HttpResponse::Ok))
+                    .route(web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok())),
+            );
+        })
+        .await;
+        let mut client = BrowserClient::new();
+
+        let resp = client.call(&srv, TestRequest::with_uri("/")).await;
+        assert_eq!(get_cookie_from_resp(&resp).value(), "fixed-token");
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "fixed-token"));
+        let resp = client.call(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn on_validated_is_called_for_valid_requests() {
+        let validated = Rc::new(RefCell::new(Vec::new()));
+        let csrf = {
+            let validated = Rc::clone(&validated);
+            CsrfMiddleware::<StdRng>::new()
+                .set_cookie(Method::GET, "/")
+                .on_validated(move |req| {
+                    validated
+                        .borrow_mut()
+                        .push(format!("{} {}", req.method(), req.path()));
+                })
+        };
+        let srv = app_with_csrf(csrf, |cfg| {
+            cfg.service(
+                web::resource("/")
+                    .route(web::get().to(HttpResponse::Ok))
+                    .route(web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok())),
+            );
+        })
+        .await;
+        let mut client = BrowserClient::new();
+
+        client.call(&srv, TestRequest::with_uri("/")).await;
+        let token = client
+            .cookie(DEFAULT_CSRF_COOKIE_NAME)
+            .unwrap()
+            .value()
+            .to_owned();
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "forged"));
+        let resp = client.call(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(validated.borrow().is_empty());
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token));
+        let resp = client.call(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(*validated.borrow(), ["POST /"]);
+    }
+
+    #[actix_web::test]
+    async fn token_entropy_bits_sets_token_length() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/")
+                        .token_entropy_bits(128),
+                )
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(get_cookie_from_resp(&resp).value().len(), 22);
+    }
+
+    #[actix_web::test]
+    async fn thread_rng_tokens_are_well_formed() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/")
+                        .use_thread_rng(true),
+                )
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let mut tokens = HashSet::new();
+        for _ in 0..2 {
+            let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+            let cookie = get_cookie_from_resp(&resp);
+            assert_eq!(cookie.value().len(), 43);
+            assert!(cookie
+                .value()
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_'));
+            tokens.insert(cookie.value().to_owned());
+        }
+        assert_eq!(tokens.len(), 2);
+    }
+
+    #[test]
+    fn fingerprint_tracks_configuration() {
+        let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/");
+        let fingerprint = csrf.fingerprint();
+        assert!(fingerprint.starts_with(env!("CARGO_PKG_VERSION")));
+
+        // Middleware built independently, as on each worker, agree.
+        let other = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/");
+        assert_eq!(other.fingerprint(), fingerprint);
+
+        assert_ne!(csrf.clone().http_only(false).fingerprint(), fingerprint);
+        assert_ne!(
+            csrf.set_cookie(Method::GET, "/login").fingerprint(),
+            fingerprint
+        );
+    }
+
+    #[cfg(feature = "signed")]
+    #[test]
+    fn config_summary_omits_secrets() {
+        let key = Key::generate();
+        let csrf = CsrfMiddleware::<StdRng>::new()
+            .set_cookie(Method::GET, "/login")
+            .set_cookie(Method::GET, "/")
+            .fixed_token("known-token")
+            .signing_key(key.clone());
+
+        let summary = serde_json::to_value(csrf.config_summary()).unwrap();
+        assert_eq!(
+            summary["set_cookie"],
+            serde_json::json!(["GET /", "GET /login"])
+        );
+        assert_eq!(summary["cookie_name"], DEFAULT_CSRF_COOKIE_NAME);
+        assert_eq!(summary["same_site"], "Strict");
+        assert_eq!(summary["signed"], true);
+        assert_eq!(summary["fixed_token"], cfg!(debug_assertions));
+
+        let json = summary.to_string();
+        assert!(!json.contains("known-token"));
+        assert!(!json.contains(&base64::encode(key.master())));
+    }
+
+    #[actix_web::test]
+    async fn disabled_middleware_sets_no_cookie() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/")
+                        .enabled(false),
+                )
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(header::SET_COOKIE).is_none());
+    }
+
+    #[actix_web::test]
+    async fn token_extraction_without_issuance() {
+        let srv = test::init_service(
+            App::new()
+                .route(
+                    "/optional",
+                    web::get().to(|token: Option<CsrfToken>| async move {
+                        HttpResponse::Ok()
+                            .body(token.map(CsrfToken::into_inner).unwrap_or_default())
+                    }),
+                )
+                .route(
+                    "/required",
+                    web::get().to(|_: CsrfToken| HttpResponse::Ok()),
                 ),
         )
         .await;
 
-        // First, let's get the token as a client.
-        let resp = test::call_service(&mut srv, TestRequest::with_uri("/1").to_request()).await;
+        let resp = test::call_service(&srv, TestRequest::with_uri("/optional").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(test::read_body(resp).await.is_empty());
+
+        let resp = test::call_service(&srv, TestRequest::with_uri("/required").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn optional_token_is_issued_token() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/"))
+                .route(
+                    "/",
+                    web::get().to(|token: Option<CsrfToken>| async move {
+                        HttpResponse::Ok()
+                            .body(token.map(CsrfToken::into_inner).unwrap_or_default())
+                    }),
+                ),
+        )
+        .await;
 
-        let token = get_token_from_resp(&resp);
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
         let cookie = get_cookie_from_resp(&resp);
+        assert_eq!(test::read_body(resp).await, cookie.value());
+    }
+
+    #[derive(serde::Deserialize)]
+    struct TokenForm {
+        csrf_token: CsrfToken,
+    }
+
+    impl CsrfGuarded for TokenForm {
+        fn csrf_token(&self) -> &CsrfToken {
+            &self.csrf_token
+        }
+    }
+
+    #[actix_web::test]
+    async fn oversized_form_token_is_rejected() {
+        static CALLED: AtomicBool = AtomicBool::new(false);
+
+        let srv = test::init_service(App::new().wrap(CsrfMiddleware::<StdRng>::new()).route(
+            "/",
+            web::post().to(|_: Csrf<web::Form<TokenForm>>| {
+                CALLED.store(true, Ordering::SeqCst);
+                HttpResponse::Ok()
+            }),
+        ))
+        .await;
+
+        let oversized = "a".repeat(MAX_TOKEN_LENGTH + 1);
+        let req = TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new(DEFAULT_CSRF_COOKIE_NAME, oversized.clone()))
+            .set_form([("csrf_token", oversized)])
+            .to_request();
+        let resp = test::call_service(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!CALLED.load(Ordering::SeqCst));
+
+        // The form fails to deserialize because the token is invalid.
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(
+            body.contains(&CsrfError::InvalidToken.to_string()),
+            "unexpected body: {body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn path_info_is_set() {
+        let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/{id}");
+        let srv = app_with_csrf(csrf, |cfg| {
+            cfg.service(
+                web::resource("/{id}")
+                    .route(web::get().to(|| HttpResponse::Ok()))
+                    .route(web::post().to(|| HttpResponse::Ok())),
+            );
+        })
+        .await;
+        let mut client = BrowserClient::new();
+
+        // First, let's get the token as a client.
+        client.call(&srv, TestRequest::with_uri("/1")).await;
+        let token = client
+            .cookie(DEFAULT_CSRF_COOKIE_NAME)
+            .unwrap()
+            .value()
+            .to_owned();
 
         // Now we can do another request to a protected endpoint.
         let req = TestRequest::post()
             .uri("/1")
-            .insert_header(("Cookie", cookie))
-            .insert_header((DEFAULT_CSRF_TOKEN_NAME, token))
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token));
+
+        let resp = client.call(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn csrf_errors_propagate_with_question_mark() {
+        let handler = |req: HttpRequest| async move {
+            let header = CsrfHeader::extract(&req).await?;
+            let cookie = CsrfCookie::extract(&req).await?;
+            if !cookie.validate(header) {
+                return Err(CsrfError::TokenMismatch.into());
+            }
+            Ok::<_, actix_web::Error>(HttpResponse::Ok().finish())
+        };
+
+        let srv = test::init_service(App::new().route("/", web::post().to(handler))).await;
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "sometoken"))
+            .to_request();
+        let resp = test::call_service(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let req = TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new(DEFAULT_CSRF_COOKIE_NAME, "sometoken"))
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "sometoken"))
+            .to_request();
+        let resp = test::call_service(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn unknown_path_is_not_found() {
+        let srv = test::init_service(App::new().wrap(CsrfMiddleware::<StdRng>::new()).route(
+            "/",
+            web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok()),
+        ))
+        .await;
+
+        let resp = test::call_service(&srv, TestRequest::post().uri("/unknown").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn try_build_rejects_incompatible_options() {
+        let csrf = CsrfMiddleware::<StdRng>::new;
+        assert!(csrf().try_build().is_ok());
+        assert!(csrf().domain("example.com").try_build().is_ok());
+
+        for (csrf, error) in [
+            (
+                csrf().cookie_name("not a valid name"),
+                CsrfBuildError::InvalidCookieName("not a valid name".to_owned()),
+            ),
+            (
+                csrf().legacy_cookie_names(&["old;name"]),
+                CsrfBuildError::InvalidCookieName("old;name".to_owned()),
+            ),
+            (
+                csrf().secure(false),
+                CsrfBuildError::HostPrefixRequiresSecure,
+            ),
+            (
+                csrf()
+                    .domain("example.com")
+                    .cookie_name("__Host-Csrf-Token"),
+                CsrfBuildError::HostPrefixWithDomain,
+            ),
+            (
+                csrf()
+                    .secure_prefixed_cookie_name("Csrf-Token")
+                    .secure(false),
+                CsrfBuildError::SecurePrefixRequiresSecure,
+            ),
+            (
+                csrf()
+                    .cookie_name("Csrf-Token")
+                    .same_site(Some(SameSite::None))
+                    .secure(false),
+                CsrfBuildError::SameSiteNoneRequiresSecure,
+            ),
+            (
+                csrf().token_entropy_bits(0),
+                CsrfBuildError::TokenEntropyOutOfRange(0),
+            ),
+            (
+                csrf().token_entropy_bits(usize::MAX),
+                CsrfBuildError::TokenEntropyOutOfRange(usize::MAX),
+            ),
+        ] {
+            assert_eq!(csrf.try_build(), Err(error));
+        }
+    }
+
+    #[actix_web::test]
+    async fn incompatible_options_fail_to_start() {
+        let csrf = CsrfMiddleware::<StdRng>::new().secure(false);
+        assert!(csrf.new_transform(test::ok_service()).await.is_err());
+
+        let csrf = CsrfMiddleware::<StdRng>::new();
+        assert!(csrf.new_transform(test::ok_service()).await.is_ok());
+    }
+
+    #[test]
+    fn insecure_transport_warning_is_rate_limited() {
+        let limiter = WarningLimiter::default();
+        let now = Instant::now();
+        assert!(limiter.check(now));
+        assert!(!limiter.check(now + Duration::from_secs(1)));
+        assert!(!limiter.check(now + INSECURE_TRANSPORT_WARNING_INTERVAL / 2));
+        assert!(limiter.check(now + INSECURE_TRANSPORT_WARNING_INTERVAL));
+        assert!(!limiter.check(now + INSECURE_TRANSPORT_WARNING_INTERVAL * 3 / 2));
+    }
+
+    #[actix_web::test]
+    async fn require_secure_transport_rejects_plain_http() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/")
+                        .require_secure_transport(true),
+                )
+                .service(web::resource("/").to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(resp.headers().get(header::SET_COOKIE).is_none());
+
+        let req = TestRequest::with_uri("/")
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .to_request();
+        let resp = test::call_service(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(get_cookie_from_resp(&resp).secure().unwrap_or_default());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[actix_web::test]
+    async fn counters_are_incremented() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+
+        // Counters are recorded per thread, so that tests running concurrently
+        // don't see each other's counts. Only the first test installs it.
+        let _ = DebuggingRecorder::per_thread().install();
+
+        let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/");
+        let srv = app_with_csrf(csrf, |cfg| {
+            cfg.service(
+                web::resource("/")
+                    .route(web::get().to(HttpResponse::Ok))
+                    .route(web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok())),
+            )
+            .route(
+                "/form",
+                web::post().to(|_: Csrf<web::Form<TokenForm>>| HttpResponse::Ok()),
+            );
+        })
+        .await;
+        let mut client = BrowserClient::new();
+
+        client.call(&srv, TestRequest::with_uri("/")).await;
+        let token = client
+            .cookie(DEFAULT_CSRF_COOKIE_NAME)
+            .unwrap()
+            .value()
+            .to_owned();
+
+        for token in [token.as_str(), "forged"] {
+            let req = TestRequest::post()
+                .uri("/")
+                .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token));
+            client.call(&srv, req).await;
+        }
+
+        // Without a header, the header extractor fails with its own CSRF error.
+        client.call(&srv, TestRequest::post().uri("/")).await;
+
+        // A body that isn't a form fails the form extractor itself.
+        let req = TestRequest::post()
+            .uri("/form")
+            .insert_header(header::ContentType::json())
+            .set_payload("{}");
+        client.call(&srv, req).await;
+
+        let snapshot = Snapshotter::current_thread_snapshot().expect("metrics to be recorded");
+        let mut counters: Vec<_> = snapshot
+            .into_vec()
+            .into_iter()
+            .filter_map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels: Vec<_> = key
+                    .labels()
+                    .map(|label| format!("{}={}", label.key(), label.value()))
+                    .collect();
+                match value {
+                    DebugValue::Counter(count) => {
+                        Some((format!("{}{labels:?}", key.name()), count))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        counters.sort();
+        assert_eq!(
+            counters,
+            [
+                ("csrf.insecure_transport[]".to_owned(), 1),
+                ("csrf.issued[]".to_owned(), 1),
+                ("csrf.rejected[\"reason=extractor_error\"]".to_owned(), 1),
+                ("csrf.rejected[\"reason=missing_cookie\"]".to_owned(), 1),
+                ("csrf.rejected[\"reason=token_mismatch\"]".to_owned(), 1),
+                ("csrf.validated[]".to_owned(), 1),
+            ]
+        );
+    }
+
+    #[cfg(feature = "signed")]
+    #[actix_web::test]
+    async fn signed_cookie_is_verified() {
+        let csrf = CsrfMiddleware::<StdRng>::new()
+            .set_cookie(Method::GET, "/")
+            .signing_key(Key::generate());
+        let srv = app_with_csrf(csrf, |cfg| {
+            cfg.service(
+                web::resource("/")
+                    .route(web::get().to(|token: CsrfToken| async move {
+                        HttpResponse::Ok().body(token.into_inner())
+                    }))
+                    .route(web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok())),
+            );
+        })
+        .await;
+        let mut client = BrowserClient::new();
+
+        let resp = client.call(&srv, TestRequest::with_uri("/")).await;
+        let token = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        let cookie = client.cookie(DEFAULT_CSRF_COOKIE_NAME).unwrap().clone();
+        assert_ne!(cookie.value(), token);
+        assert!(cookie.value().ends_with(&token));
+
+        let post = |token: &str| {
+            TestRequest::post()
+                .uri("/")
+                .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token.to_owned()))
+        };
+        let resp = client.call(&srv, post(&token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Swap the token for a forged one, but keep the original signature.
+        let signature = cookie.value().strip_suffix(&token).unwrap();
+        client.add_cookie(Cookie::new(
+            DEFAULT_CSRF_COOKIE_NAME,
+            format!("{signature}forged"),
+        ));
+        let resp = client.call(&srv, post("forged")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // An unsigned cookie planted without the key is rejected as well.
+        client.add_cookie(Cookie::new(DEFAULT_CSRF_COOKIE_NAME, "forged"));
+        let resp = client.call(&srv, post("forged")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[cfg(feature = "signed")]
+    #[actix_web::test]
+    async fn signed_roundtrip_check() {
+        let csrf = CsrfMiddleware::<StdRng>::new().signing_key(Key::generate());
+        let result = crate::test::roundtrip_check(csrf, Method::POST, "/", []).await;
+        assert_eq!(result, Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn handler_can_reissue_token() {
+        let reissue = || {
+            let mut res = HttpResponse::Ok().finish();
+            res.extensions_mut().insert(CsrfAction::Reissue);
+            res
+        };
+
+        let srv = test::init_service(
+            App::new()
+                .wrap(CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/"))
+                .service(
+                    web::resource("/")
+                        .route(web::get().to(|token: CsrfToken| async move {
+                            let mut res = HttpResponse::Ok().body(token.into_inner());
+                            res.extensions_mut().insert(CsrfAction::Reissue);
+                            res
+                        }))
+                        .route(web::post().to(move || std::future::ready(reissue()))),
+                ),
+        )
+        .await;
+
+        let resp = test::call_service(&srv, TestRequest::post().uri("/").to_request()).await;
+        assert!(!get_cookie_from_resp(&resp).value().is_empty());
+
+        // The reissued token replaces the one issued for the request.
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        let cookie = get_cookie_from_resp(&resp);
+        assert_ne!(test::read_body(resp).await, cookie.value());
+    }
+
+    #[tokio::test]
+    async fn foreign_redirects_do_not_receive_cookie() {
+        let redirect = |req: HttpRequest| {
+            let location = req.headers().get("x-location").cloned().unwrap();
+            let res = HttpResponse::Found()
+                .insert_header((header::LOCATION, location))
+                .finish();
+            std::future::ready(res)
+        };
+
+        let srv = test::init_service(
+            App::new()
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/")
+                        .skip_cookie_on_foreign_redirects(true),
+                )
+                .service(web::resource("/").to(redirect)),
+        )
+        .await;
+
+        for (location, issued) in [
+            ("/login", true),
+            ("login?next=%2F", true),
+            ("https://example.com/login", true),
+            ("https://EXAMPLE.com:8443/login", true),
+            ("https://auth.example.net/authorize", false),
+            ("//auth.example.net/authorize", false),
+            ("https://exa mple.com/login", false),
+        ] {
+            let req = TestRequest::with_uri("/")
+                .insert_header((header::HOST, "example.com:8080"))
+                .insert_header(("x-location", location))
+                .to_request();
+            let resp = test::call_service(&srv, req).await;
+            assert_eq!(resp.status(), StatusCode::FOUND);
+            assert_eq!(
+                resp.response().cookies().count(),
+                usize::from(issued),
+                "{location}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects_within_cookie_domain_receive_cookie() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/")
+                        .domain("example.com")
+                        .skip_cookie_on_foreign_redirects(true),
+                )
+                .service(web::resource("/").to(|| async {
+                    HttpResponse::Found()
+                        .insert_header((header::LOCATION, "https://app.example.com/"))
+                        .finish()
+                })),
+        )
+        .await;
+
+        let req = TestRequest::with_uri("/")
+            .insert_header((header::HOST, "www.example.com"))
+            .to_request();
+        let resp = test::call_service(&srv, req).await;
+        assert_eq!(resp.response().cookies().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn foreign_redirects_receive_cookie_by_default() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/"))
+                .service(web::resource("/").to(|| async {
+                    HttpResponse::Found()
+                        .insert_header((header::LOCATION, "https://auth.example.net/"))
+                        .finish()
+                })),
+        )
+        .await;
+
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(resp.response().cookies().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn head_mirrors_get_issuance() {
+        for (mirror, issued) in [(true, 1), (false, 0)] {
+            let srv = test::init_service(
+                App::new()
+                    .wrap(
+                        CsrfMiddleware::<StdRng>::new()
+                            .set_cookie(Method::GET, "/")
+                            .head_mirrors_get(mirror),
+                    )
+                    .service(web::resource("/").to(HttpResponse::Ok)),
+            )
+            .await;
+
+            let resp = test::call_service(&srv, TestRequest::get().uri("/").to_request()).await;
+            assert_eq!(resp.response().cookies().count(), 1);
+
+            let req = TestRequest::default()
+                .method(Method::HEAD)
+                .uri("/")
+                .to_request();
+            let resp = test::call_service(&srv, req).await;
+            assert_eq!(resp.response().cookies().count(), issued);
+        }
+    }
+
+    #[tokio::test]
+    async fn ambiguous_cookie_is_rejected() {
+        for (strict, status) in [
+            (true, StatusCode::UNPROCESSABLE_ENTITY),
+            (false, StatusCode::OK),
+        ] {
+            let srv = test::init_service(
+                App::new()
+                    .wrap(CsrfMiddleware::<StdRng>::new().reject_ambiguous_cookie(strict))
+                    .service(
+                        web::resource("/")
+                            .route(web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok())),
+                    ),
+            )
+            .await;
+
+            let cookies = format!("{DEFAULT_CSRF_COOKIE_NAME}=a; {DEFAULT_CSRF_COOKIE_NAME}=b");
+            let req = TestRequest::post()
+                .uri("/")
+                .insert_header((header::COOKIE, cookies))
+                .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "a"))
+                .to_request();
+            let resp = test::call_service(&srv, req).await;
+            assert_eq!(resp.status(), status);
+
+            // Duplicates with the same value are not ambiguous.
+            let cookies = format!("{DEFAULT_CSRF_COOKIE_NAME}=a; {DEFAULT_CSRF_COOKIE_NAME}=a");
+            let req = TestRequest::post()
+                .uri("/")
+                .insert_header((header::COOKIE, cookies))
+                .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), "a"))
+                .to_request();
+            let resp = test::call_service(&srv, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+    }
+
+    /// Stores the CSRF cookie in a header pair instead of the cookie headers.
+    struct HeaderPair;
+
+    impl CookieBackend for HeaderPair {
+        fn read(&self, req: &HttpRequest, _name: &str) -> Option<String> {
+            let value = req.headers().get("x-csrf-cookie")?;
+            value.to_str().ok().map(str::to_owned)
+        }
+
+        fn write(&self, headers: &mut header::HeaderMap, cookie: &Cookie<'_>) {
+            let value = HeaderValue::from_str(cookie.value()).unwrap();
+            headers.insert(header::HeaderName::from_static("x-set-csrf-cookie"), value);
+        }
+    }
+
+    #[actix_web::test]
+    async fn cookie_backend_replaces_cookie_headers() {
+        let csrf = CsrfMiddleware::<StdRng>::new()
+            .set_cookie(Method::GET, "/")
+            .cookie_backend(HeaderPair);
+        let srv = app_with_csrf(csrf, |cfg| {
+            cfg.service(
+                web::resource("/")
+                    .route(web::get().to(|token: CsrfToken| async move {
+                        HttpResponse::Ok().body(token.into_inner())
+                    }))
+                    .route(web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok())),
+            );
+        })
+        .await;
+
+        // The token is written to the backend instead of `Set-Cookie`.
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert!(resp.headers().get(header::SET_COOKIE).is_none());
+        let stored = resp.headers().get("x-set-csrf-cookie").unwrap().clone();
+        let token = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(stored, token.as_str());
+
+        let post = |stored: &str, token: &str| {
+            TestRequest::post()
+                .uri("/")
+                .insert_header(("x-csrf-cookie", stored.to_owned()))
+                .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token.to_owned()))
+                .to_request()
+        };
+        let resp = test::call_service(&srv, post(&token, &token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&srv, post(&token, "forged")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // The cookie header is no longer read.
+        let req = TestRequest::post()
+            .uri("/")
+            .cookie(Cookie::new(DEFAULT_CSRF_COOKIE_NAME, token.clone()))
+            .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token))
             .to_request();
+        let resp = test::call_service(&srv, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    /// Prefixes tokens with the region from the `x-region` header.
+    struct RegionCodec;
+
+    fn region(req: &HttpRequest) -> &str {
+        req.headers()
+            .get("x-region")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+    }
+
+    impl TokenCodec for RegionCodec {
+        fn encode(&self, req: &HttpRequest, secret: String) -> String {
+            format!("{}:{}", region(req), secret)
+        }
+
+        fn decode(&self, req: &HttpRequest, token: &str) -> Result<String, CsrfError> {
+            match token.split_once(':') {
+                Some((prefix, secret)) if prefix == region(req) => Ok(secret.to_owned()),
+                _ => Err(CsrfError::TokenMismatch),
+            }
+        }
+    }
 
-        let resp = test::call_service(&mut srv, req).await;
+    #[actix_web::test]
+    async fn token_codec_checks_public_parts() {
+        let csrf = CsrfMiddleware::<StdRng>::new()
+            .set_cookie(Method::GET, "/")
+            .token_codec(RegionCodec);
+        let srv = app_with_csrf(csrf, |cfg| {
+            cfg.service(
+                web::resource("/")
+                    .route(web::get().to(|token: CsrfToken| async move {
+                        HttpResponse::Ok().body(token.into_inner())
+                    }))
+                    .route(web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok())),
+            );
+        })
+        .await;
+        let mut client = BrowserClient::new();
+
+        let req = TestRequest::with_uri("/").insert_header(("x-region", "us-east"));
+        let resp = client.call(&srv, req).await;
+        let token = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(token.starts_with("us-east:"));
+        assert_eq!(
+            client.cookie(DEFAULT_CSRF_COOKIE_NAME).unwrap().value(),
+            token
+        );
+
+        let post = |region: &str, token: &str| {
+            TestRequest::post()
+                .uri("/")
+                .insert_header(("x-region", region.to_owned()))
+                .insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token.to_owned()))
+        };
+        let resp = client.call(&srv, post("us-east", &token)).await;
         assert_eq!(resp.status(), StatusCode::OK);
+
+        // The secret matches, but the region doesn't.
+        let resp = client.call(&srv, post("eu-west", &token)).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // The region matches, but the secret doesn't.
+        let resp = client.call(&srv, post("us-east", "us-east:forged")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn expiry_is_aligned_with_session_cookie() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/login")
+                        .set_cookie(Method::GET, "/")
+                        .align_expiry_with("session"),
+                )
+                .route(
+                    "/login",
+                    web::get().to(|| async {
+                        let session = Cookie::build("session", "abc")
+                            .max_age(cookie::time::Duration::hours(2))
+                            .finish();
+                        HttpResponse::Ok().cookie(session).finish()
+                    }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let resp = test::call_service(&srv, TestRequest::with_uri("/login").to_request()).await;
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|v| v.name() == DEFAULT_CSRF_COOKIE_NAME)
+            .unwrap();
+        assert_eq!(cookie.max_age(), Some(cookie::time::Duration::hours(2)));
+
+        // Without a session cookie in the response, the CSRF cookie is unchanged.
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(get_cookie_from_resp(&resp).max_age(), None);
+    }
+
+    #[actix_web::test]
+    async fn expiry_falls_back_without_live_session_cookie() {
+        let srv = test::init_service(
+            App::new()
+                .wrap(
+                    CsrfMiddleware::<StdRng>::new()
+                        .set_cookie(Method::GET, "/logout")
+                        .set_cookie(Method::GET, "/")
+                        .align_expiry_with("session")
+                        .max_age(cookie::time::Duration::minutes(30)),
+                )
+                .route(
+                    "/logout",
+                    web::get().to(|| async {
+                        let mut res = HttpResponse::Ok().finish();
+                        res.add_removal_cookie(&Cookie::new("session", "")).unwrap();
+                        res
+                    }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // Without a session cookie, the configured default applies.
+        let resp = test::call_service(&srv, TestRequest::with_uri("/").to_request()).await;
+        assert_eq!(
+            get_cookie_from_resp(&resp).max_age(),
+            Some(cookie::time::Duration::minutes(30))
+        );
+
+        // Removing the session doesn't remove the freshly issued CSRF cookie.
+        let resp = test::call_service(&srv, TestRequest::with_uri("/logout").to_request()).await;
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|v| v.name() == DEFAULT_CSRF_COOKIE_NAME)
+            .unwrap();
+        assert!(!cookie.value().is_empty());
+        assert_eq!(cookie.max_age(), Some(cookie::time::Duration::minutes(30)));
     }
 }
@@ -0,0 +1,43 @@
+//! Signing of CSRF cookies, enabled by the `signed` feature.
+
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use cookie::{Cookie, CookieJar, Key};
+
+/// The key used to sign and verify CSRF cookies. It is stored in the request
+/// extensions so that extractors can verify the cookie the middleware signed.
+#[derive(Clone)]
+pub struct SigningKey(pub Rc<Key>);
+
+impl SigningKey {
+    /// Signs the cookie value, prepending its MAC.
+    pub fn sign(&self, cookie: Cookie<'static>) -> Cookie<'static> {
+        let name = cookie.name().to_owned();
+        let mut jar = CookieJar::new();
+        jar.signed_mut(&self.0).add(cookie);
+        jar.get(&name)
+            .cloned()
+            .expect("signed cookie to be in the jar")
+    }
+
+    /// Verifies the cookie, returning it with the MAC stripped from its value
+    /// if it is authentic.
+    pub fn verify(&self, cookie: Cookie<'static>) -> Option<Cookie<'static>> {
+        CookieJar::new().signed(&self.0).verify(cookie)
+    }
+}
+
+impl PartialEq for SigningKey {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SigningKey {}
+
+impl Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SigningKey")
+    }
+}
@@ -0,0 +1,73 @@
+//! A serializable view of the middleware configuration.
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::{CsrfMiddleware, Inner};
+
+/// A summary of the non-secret configuration of a [`CsrfMiddleware`], which
+/// can be serialized, such as to render the CSRF policy in an admin UI.
+///
+/// Secrets are never serialized. The fixed token and the signing key only
+/// report whether they are set, as do callbacks.
+///
+/// ```
+/// # use actix_csrf::CsrfMiddleware;
+/// # use actix_web::http::Method;
+/// # use actix_web::HttpResponse;
+/// # use rand::rngs::StdRng;
+/// let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/login");
+/// let res = HttpResponse::Ok().json(csrf.config_summary());
+/// ```
+#[derive(Debug)]
+pub struct CsrfConfigSummary<'a, Rng>(&'a Inner<Rng>);
+
+impl<Rng> CsrfMiddleware<Rng> {
+    /// Returns a serializable summary of the non-secret configuration.
+    #[must_use]
+    pub const fn config_summary(&self) -> CsrfConfigSummary<'_, Rng> {
+        CsrfConfigSummary(&self.inner)
+    }
+}
+
+impl<Rng> Serialize for CsrfConfigSummary<'_, Rng> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let inner = self.0;
+
+        #[cfg(feature = "signed")]
+        let signed = inner.signing_key.is_some();
+        #[cfg(not(feature = "signed"))]
+        let signed = false;
+
+        let mut state = serializer.serialize_struct("CsrfConfigSummary", 23)?;
+        state.serialize_field("enabled", &inner.csrf_enabled)?;
+        state.serialize_field("set_cookie", &inner.set_cookie_routes())?;
+        state.serialize_field("cookie_name", inner.cookie_name.as_str())?;
+        state.serialize_field("legacy_cookie_names", &inner.legacy_cookie_names)?;
+        state.serialize_field("derived_cookie_name", &inner.cookie_name_fn.is_some())?;
+        state.serialize_field("http_only", &inner.http_only)?;
+        state.serialize_field("secure", &inner.secure)?;
+        state.serialize_field("same_site", &inner.same_site.map(|v| v.to_string()))?;
+        state.serialize_field("domain", &inner.domain)?;
+        state.serialize_field("align_expiry_with", &inner.align_expiry_with)?;
+        state.serialize_field(
+            "max_age",
+            &inner.max_age.map(cookie::time::Duration::whole_seconds),
+        )?;
+        state.serialize_field("token_entropy_bits", &inner.token_entropy_bits)?;
+        state.serialize_field("use_thread_rng", &inner.use_thread_rng)?;
+        state.serialize_field("fixed_token", &inner.fixed_token.is_some())?;
+        state.serialize_field("signed", &signed)?;
+        state.serialize_field("on_validated", &inner.on_validated.is_some())?;
+        state.serialize_field("cookie_backend", &inner.cookie_backend.is_some())?;
+        state.serialize_field("token_codec", &inner.token_codec.is_some())?;
+        state.serialize_field("require_secure_transport", &inner.require_secure_transport)?;
+        state.serialize_field(
+            "skip_cookie_on_foreign_redirects",
+            &inner.skip_cookie_on_foreign_redirects,
+        )?;
+        state.serialize_field("head_mirrors_get", &inner.head_mirrors_get)?;
+        state.serialize_field("reject_ambiguous_cookie", &inner.reject_ambiguous_cookie)?;
+        state.serialize_field("fingerprint", &format!("{:016x}", inner.fingerprint()))?;
+        state.end()
+    }
+}
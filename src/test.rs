@@ -0,0 +1,339 @@
+//! Helpers for testing applications protected by [`CsrfMiddleware`], available
+//! with the `test-helpers` feature.
+//!
+//! The helpers only build on actix-web's own test utilities, so they can be used
+//! in `#[actix_web::test]` functions without any runtime specific setup.
+//!
+//! ```
+//! use actix_csrf::extractor::{Csrf, CsrfHeader};
+//! use actix_csrf::test::{app_with_csrf, BrowserClient};
+//! use actix_csrf::CsrfMiddleware;
+//! use actix_web::http::{Method, StatusCode};
+//! use actix_web::test::TestRequest;
+//! use actix_web::{web, HttpResponse};
+//! use rand::rngs::StdRng;
+//!
+//! # actix_web::rt::System::new().block_on(async {
+//! let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/form");
+//! let app = app_with_csrf(csrf, |cfg| {
+//!     cfg.route("/form", web::get().to(HttpResponse::Ok))
+//!         .route("/form", web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok()));
+//! })
+//! .await;
+//!
+//! let mut client = BrowserClient::new();
+//! client.call(&app, TestRequest::with_uri("/form")).await;
+//! let token = client.cookie("__Host-Csrf-Token").unwrap().value().to_owned();
+//!
+//! let req = TestRequest::post()
+//!     .uri("/form")
+//!     .insert_header(("csrf-token", token));
+//! assert_eq!(client.call(&app, req).await.status(), StatusCode::OK);
+//! # });
+//! ```
+//!
+//! [`CsrfMiddleware`]: crate::CsrfMiddleware
+
+use std::cell::{Cell, RefCell};
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::test::{self, TestRequest};
+use actix_web::web::{self, ServiceConfig};
+use actix_web::{App, FromRequest, HttpRequest, HttpResponse};
+use cookie::time::Duration;
+use cookie::{Cookie, CookieJar};
+
+use crate::extractor::{Csrf, CsrfCookie, CsrfExtractorError, CsrfHeader, CsrfToken};
+use crate::{bypass, CsrfError, CsrfMiddleware, TokenRng, DEFAULT_CSRF_HEADER_NAME};
+
+/// Initializes a test service for an app wrapped in the provided middleware,
+/// with the services registered by `configure`.
+///
+/// # Panics
+///
+/// Panics if the app fails to initialize, like
+/// [`init_service`](actix_web::test::init_service), such as when the
+/// middleware's cookie options are incompatible.
+#[allow(clippy::future_not_send)] // actix-web services are not `Send`
+pub async fn app_with_csrf<Rng, F>(
+    csrf: CsrfMiddleware<Rng>,
+    configure: F,
+) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error>
+where
+    Rng: TokenRng + Clone + 'static,
+    F: FnOnce(&mut ServiceConfig),
+{
+    test::init_service(App::new().wrap(csrf).configure(configure)).await
+}
+
+/// A test client that keeps cookies across requests, like a browser does.
+///
+/// Cookies set by a response are sent with every following request, and
+/// cookies removed by a response are no longer sent. This lets tests follow a
+/// token being issued, submitted, and reissued without handling cookies by
+/// hand.
+///
+/// ```
+/// # use actix_csrf::test::BrowserClient;
+/// # use actix_csrf::CsrfMiddleware;
+/// # use actix_web::http::Method;
+/// # use actix_web::test::{init_service, TestRequest};
+/// # use actix_web::{web, App, HttpResponse};
+/// # use rand::rngs::StdRng;
+/// # actix_web::rt::System::new().block_on(async {
+/// let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/");
+/// let app = init_service(
+///     App::new()
+///         .wrap(csrf)
+///         .route("/", web::get().to(HttpResponse::Ok)),
+/// )
+/// .await;
+///
+/// let mut client = BrowserClient::new();
+/// client.call(&app, TestRequest::with_uri("/")).await;
+/// assert!(client.cookie("__Host-Csrf-Token").is_some());
+/// # });
+/// ```
+#[derive(Clone, Default, Debug)]
+pub struct BrowserClient {
+    jar: CookieJar,
+}
+
+impl BrowserClient {
+    /// Creates a client without any cookies.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends the request with the stored cookies, and stores the cookies set by
+    /// the response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the service returns an error, like
+    /// [`call_service`](actix_web::test::call_service).
+    #[allow(clippy::future_not_send)] // actix-web services are not `Send`
+    pub async fn call<S, B, E>(&mut self, app: &S, req: TestRequest) -> ServiceResponse<B>
+    where
+        S: Service<Request, Response = ServiceResponse<B>, Error = E>,
+        E: Debug,
+    {
+        let req = self
+            .jar
+            .iter()
+            .fold(req, |req, cookie| req.cookie(cookie.clone()));
+        let resp = test::call_service(app, req.to_request()).await;
+
+        for cookie in resp.response().cookies() {
+            let cookie = cookie.into_owned();
+            if is_removal(&cookie) {
+                self.jar.force_remove(&cookie);
+            } else {
+                self.jar.add(cookie);
+            }
+        }
+
+        resp
+    }
+
+    /// Returns the stored cookie with the name, if any.
+    #[must_use]
+    pub fn cookie(&self, name: &str) -> Option<&Cookie<'static>> {
+        self.jar.get(name)
+    }
+
+    /// Stores a cookie, as if a response had set it.
+    pub fn add_cookie(&mut self, cookie: Cookie<'static>) {
+        self.jar.add(cookie);
+    }
+}
+
+/// Checks that a token issued by the middleware passes validation, without
+/// writing any handlers.
+///
+/// The middleware is set to issue a token for `method` and `path`, and a first
+/// request mints one. A second request to the same `method` and `path` then
+/// submits the token in the `csrf-token` header, with the issued cookie, and
+/// is validated like [`Csrf<CsrfHeader>`](crate::extractor::Csrf) would. Both
+/// requests carry `headers`, such as `Host` or `X-Forwarded-Proto`, for
+/// configurations that depend on them.
+///
+/// This gives quick confidence that a configuration, such as one with a
+/// [`signing_key`](CsrfMiddleware::signing_key) or a
+/// [`cookie_name_fn`](CsrfMiddleware::cookie_name_fn), issues tokens that it
+/// accepts.
+///
+/// ```
+/// use actix_csrf::test::roundtrip_check;
+/// use actix_csrf::CsrfMiddleware;
+/// use actix_web::http::Method;
+/// use rand::rngs::StdRng;
+///
+/// # actix_web::rt::System::new().block_on(async {
+/// let csrf = CsrfMiddleware::<StdRng>::new();
+/// assert_eq!(roundtrip_check(csrf, Method::POST, "/", []).await, Ok(()));
+/// # });
+/// ```
+///
+/// # Errors
+///
+/// Returns the reason the token was rejected, such as
+/// [`CsrfError::MissingCookie`] if no cookie was issued.
+///
+/// # Panics
+///
+/// Panics if the app fails to initialize, like [`app_with_csrf`].
+#[allow(clippy::future_not_send)] // actix-web services are not `Send`
+pub async fn roundtrip_check<Rng>(
+    csrf: CsrfMiddleware<Rng>,
+    method: Method,
+    path: &str,
+    headers: impl IntoIterator<Item = (HeaderName, HeaderValue)>,
+) -> Result<(), CsrfError>
+where
+    Rng: TokenRng + Clone + 'static,
+{
+    let headers: Vec<_> = headers.into_iter().collect();
+    let minted = Rc::new(Cell::new(false));
+    let result = Rc::new(RefCell::new(None));
+
+    let handler = {
+        let minted = Rc::clone(&minted);
+        let result = Rc::clone(&result);
+        move |req: HttpRequest| {
+            let minted = Rc::clone(&minted);
+            let result = Rc::clone(&result);
+            async move {
+                if minted.replace(true) {
+                    *result.borrow_mut() = Some(validate(&req).await);
+                }
+                HttpResponse::Ok().finish()
+            }
+        }
+    };
+    let csrf = csrf.set_cookie(method.clone(), path);
+    let app = app_with_csrf(csrf, |cfg| {
+        cfg.default_service(web::to(handler));
+    })
+    .await;
+
+    let request = || {
+        headers.iter().cloned().fold(
+            TestRequest::default().method(method.clone()).uri(path),
+            TestRequest::insert_header,
+        )
+    };
+    let mut client = BrowserClient::new();
+    let resp = client.call(&app, request()).await;
+    let token = CsrfToken::extract(resp.request()).await?;
+
+    let req = request().insert_header((DEFAULT_CSRF_HEADER_NAME.clone(), token.into_inner()));
+    client.call(&app, req).await;
+    let result = result.borrow_mut().take();
+    result.unwrap_or(Err(CsrfError::TokenNotIssued))
+}
+
+/// Validates the request like [`Csrf<CsrfHeader>`], reporting why it failed.
+#[allow(clippy::future_not_send)] // actix-web requests are not `Send`
+async fn validate(req: &HttpRequest) -> Result<(), CsrfError> {
+    match Csrf::<CsrfHeader>::extract(req).await {
+        Ok(_) => Ok(()),
+        Err(CsrfExtractorError::Inner(e)) => Err(e),
+        Err(CsrfExtractorError::InvalidToken) => {
+            CsrfCookie::extract(req).await?;
+            Err(CsrfError::TokenMismatch)
+        }
+    }
+}
+
+/// Runs the future returned by `f` with token validation turned off, for tests
+/// that exercise handlers behind [`Csrf`](crate::extractor::Csrf) without
+/// obtaining a token first.
+///
+/// While the future is polled, the [`Csrf`](crate::extractor::Csrf) extractor
+/// accepts any token the underlying extractor finds, such as a placeholder
+/// header value, without comparing it to the cookie. Requests passed to the
+/// middleware inside the scope skip validation even if their extractors are
+/// polled later. Validation is unaffected on other threads and outside of the
+/// scope.
+///
+/// ```
+/// use actix_csrf::extractor::{Csrf, CsrfHeader};
+/// use actix_csrf::test::{app_with_csrf, with_csrf_disabled, BrowserClient};
+/// use actix_csrf::CsrfMiddleware;
+/// use actix_web::http::StatusCode;
+/// use actix_web::test::TestRequest;
+/// use actix_web::{web, HttpResponse};
+/// use rand::rngs::StdRng;
+///
+/// # actix_web::rt::System::new().block_on(async {
+/// let app = app_with_csrf(CsrfMiddleware::<StdRng>::new(), |cfg| {
+///     cfg.route("/", web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok()));
+/// })
+/// .await;
+///
+/// let mut client = BrowserClient::new();
+/// let req = TestRequest::post().uri("/").insert_header(("csrf-token", "any"));
+/// let resp = with_csrf_disabled(|| client.call(&app, req)).await;
+/// assert_eq!(resp.status(), StatusCode::OK);
+/// # });
+/// ```
+pub async fn with_csrf_disabled<F, Fut>(f: F) -> Fut::Output
+where
+    F: FnOnce() -> Fut,
+    Fut: Future,
+{
+    let inner = {
+        let _guard = DisabledGuard::new();
+        Box::pin(f())
+    };
+    CsrfDisabled { inner }.await
+}
+
+/// Polls the inner future with validation turned off.
+struct CsrfDisabled<Fut> {
+    inner: Pin<Box<Fut>>,
+}
+
+impl<Fut: Future> Future for CsrfDisabled<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _guard = DisabledGuard::new();
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+/// Turns validation off until dropped, restoring the previous state even if the
+/// scope panics.
+struct DisabledGuard {
+    previous: bool,
+}
+
+impl DisabledGuard {
+    fn new() -> Self {
+        Self {
+            previous: bypass::DISABLED.with(|disabled| disabled.replace(true)),
+        }
+    }
+}
+
+impl Drop for DisabledGuard {
+    fn drop(&mut self) {
+        bypass::DISABLED.with(|disabled| disabled.set(self.previous));
+    }
+}
+
+/// Checks if the cookie removes a cookie instead of setting it.
+fn is_removal(cookie: &Cookie) -> bool {
+    cookie.max_age().map_or(false, |age| age <= Duration::ZERO)
+}
@@ -0,0 +1,100 @@
+//! Structured tokens, made of a secret and public parts.
+
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use actix_web::{HttpMessage, HttpRequest};
+
+use crate::CsrfError;
+
+/// Splits tokens into their secret and public parts, such as a routing prefix.
+///
+/// The middleware encodes every generated secret into the issued token, and
+/// the [`Csrf`](crate::extractor::Csrf) extractor decodes both the cookie and
+/// the submitted token before comparing them. Only the decoded secrets are
+/// compared, in constant time, so a codec can check the public parts against
+/// the request instead. The default codec is [`IdentityCodec`].
+///
+/// ```
+/// use actix_csrf::{CsrfError, CsrfMiddleware, TokenCodec};
+/// use actix_web::HttpRequest;
+/// use rand::rngs::StdRng;
+///
+/// /// Prefixes tokens with the region from the `x-region` header.
+/// struct RegionCodec;
+///
+/// fn region(req: &HttpRequest) -> &str {
+///     req.headers()
+///         .get("x-region")
+///         .and_then(|v| v.to_str().ok())
+///         .unwrap_or("default")
+/// }
+///
+/// impl TokenCodec for RegionCodec {
+///     fn encode(&self, req: &HttpRequest, secret: String) -> String {
+///         format!("{}:{}", region(req), secret)
+///     }
+///
+///     fn decode(&self, req: &HttpRequest, token: &str) -> Result<String, CsrfError> {
+///         match token.split_once(':') {
+///             Some((prefix, secret)) if prefix == region(req) => Ok(secret.to_owned()),
+///             _ => Err(CsrfError::TokenMismatch),
+///         }
+///     }
+/// }
+///
+/// let csrf = CsrfMiddleware::<StdRng>::new().token_codec(RegionCodec);
+/// ```
+pub trait TokenCodec {
+    /// Encodes a generated secret into the token issued for the request.
+    fn encode(&self, req: &HttpRequest, secret: String) -> String;
+
+    /// Decodes a token received with the request into its secret.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the token is malformed, or its public parts don't
+    /// match the request. The request is then rejected with this error.
+    fn decode(&self, req: &HttpRequest, token: &str) -> Result<String, CsrfError>;
+}
+
+/// The default [`TokenCodec`], which uses the whole token as the secret.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub struct IdentityCodec;
+
+impl TokenCodec for IdentityCodec {
+    fn encode(&self, _req: &HttpRequest, secret: String) -> String {
+        secret
+    }
+
+    fn decode(&self, _req: &HttpRequest, token: &str) -> Result<String, CsrfError> {
+        Ok(token.to_owned())
+    }
+}
+
+/// A configured token codec. It is stored in the request extensions so that
+/// the [`Csrf`](crate::extractor::Csrf) extractor decodes tokens the same way
+/// the middleware encoded them.
+#[derive(Clone)]
+pub struct SharedTokenCodec(pub Rc<dyn TokenCodec>);
+
+impl SharedTokenCodec {
+    /// Returns the codec configured for the request, if any.
+    pub fn get(req: &HttpRequest) -> Option<Self> {
+        req.extensions().get::<Self>().cloned()
+    }
+}
+
+impl PartialEq for SharedTokenCodec {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::as_ptr(&self.0).cast::<()>() == Rc::as_ptr(&other.0).cast::<()>()
+    }
+}
+
+impl Eq for SharedTokenCodec {}
+
+impl Debug for SharedTokenCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedTokenCodec")
+    }
+}
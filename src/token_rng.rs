@@ -1,7 +1,20 @@
 //! Token generators and related crypto functions.
 
 use base64::URL_SAFE_NO_PAD;
-use rand::{CryptoRng, Error, Fill, RngCore};
+use rand::{CryptoRng, Error, RngCore};
+
+/// The entropy of tokens generated by [`TokenRng::generate_token`], in bits.
+pub const DEFAULT_TOKEN_ENTROPY_BITS: usize = 256;
+
+/// The least entropy [`CsrfMiddleware::token_entropy_bits`] accepts, in bits.
+///
+/// [`CsrfMiddleware::token_entropy_bits`]: crate::CsrfMiddleware::token_entropy_bits
+pub const MIN_TOKEN_ENTROPY_BITS: usize = 128;
+
+/// The most entropy [`CsrfMiddleware::token_entropy_bits`] accepts, in bits.
+///
+/// [`CsrfMiddleware::token_entropy_bits`]: crate::CsrfMiddleware::token_entropy_bits
+pub const MAX_TOKEN_ENTROPY_BITS: usize = 2048;
 
 /// Used to generate CSRF tokens.
 ///
@@ -20,12 +33,108 @@ pub trait TokenRng: CryptoRng {
     ///
     /// Returns an error if the underlying RNG fails to generate a token.
     fn generate_token(&mut self) -> Result<String, Error>;
+
+    /// Generates a CSRF token with at least the provided amount of entropy, in
+    /// bits.
+    ///
+    /// For blanket implementations, the entropy is rounded up to whole bytes,
+    /// and each character of the base64 encoded token carries 6 bits. For
+    /// example, 128 bits of entropy produce a 22 character token.
+    ///
+    /// The entropy is clamped to between [`MIN_TOKEN_ENTROPY_BITS`] and
+    /// [`MAX_TOKEN_ENTROPY_BITS`], so that a token is never empty nor
+    /// unbounded in size.
+    ///
+    /// The default implementation assumes each token from
+    /// [`generate_token`](Self::generate_token) carries
+    /// [`DEFAULT_TOKEN_ENTROPY_BITS`], and concatenates as many tokens as
+    /// needed to reach the requested entropy.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying RNG fails to generate a token.
+    fn generate_token_with_entropy(&mut self, bits: usize) -> Result<String, Error> {
+        let bits = clamp_entropy(bits);
+        let count = (bits + DEFAULT_TOKEN_ENTROPY_BITS - 1) / DEFAULT_TOKEN_ENTROPY_BITS;
+        let mut token = String::new();
+        for _ in 0..count {
+            token.push_str(&self.generate_token()?);
+        }
+        Ok(token)
+    }
 }
 
 impl<Rng: CryptoRng + RngCore> TokenRng for Rng {
     fn generate_token(&mut self) -> Result<String, Error> {
-        let mut buf = [0; 32];
-        buf.try_fill(self)?;
+        self.generate_token_with_entropy(DEFAULT_TOKEN_ENTROPY_BITS)
+    }
+
+    fn generate_token_with_entropy(&mut self, bits: usize) -> Result<String, Error> {
+        let mut buf = vec![0; (clamp_entropy(bits) + 7) / 8];
+        self.try_fill_bytes(&mut buf)?;
         Ok(base64::encode_config(buf, URL_SAFE_NO_PAD))
     }
 }
+
+/// Clamps the requested entropy to the supported range.
+fn clamp_entropy(bits: usize) -> usize {
+    bits.clamp(MIN_TOKEN_ENTROPY_BITS, MAX_TOKEN_ENTROPY_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{CryptoRng, Error, SeedableRng};
+
+    use super::TokenRng;
+
+    /// A generator that only implements [`TokenRng::generate_token`].
+    struct Counter(u8);
+
+    impl CryptoRng for Counter {}
+
+    impl TokenRng for Counter {
+        fn generate_token(&mut self) -> Result<String, Error> {
+            self.0 += 1;
+            Ok(self.0.to_string())
+        }
+    }
+
+    #[test]
+    fn entropy_defaults_to_concatenated_tokens() {
+        assert_eq!(Counter(0).generate_token_with_entropy(128).unwrap(), "1");
+        assert_eq!(Counter(0).generate_token_with_entropy(256).unwrap(), "1");
+        assert_eq!(Counter(0).generate_token_with_entropy(257).unwrap(), "12");
+        assert_eq!(Counter(0).generate_token_with_entropy(768).unwrap(), "123");
+    }
+
+    #[test]
+    fn entropy_is_clamped() {
+        let mut rng = StdRng::from_entropy();
+        // 128 bits in unpadded base64.
+        assert_eq!(rng.generate_token_with_entropy(0).unwrap().len(), 22);
+        // 2048 bits in unpadded base64.
+        let max = rng.generate_token_with_entropy(usize::MAX).unwrap();
+        assert_eq!(max.len(), 342);
+
+        assert_eq!(Counter(0).generate_token_with_entropy(0).unwrap(), "1");
+        let max = Counter(0).generate_token_with_entropy(usize::MAX).unwrap();
+        assert_eq!(max, "12345678");
+    }
+
+    #[test]
+    fn generated_tokens_are_unique_and_url_safe() {
+        let mut rng = StdRng::from_entropy();
+        let first = rng.generate_token().unwrap();
+        let second = rng.generate_token().unwrap();
+
+        assert_ne!(first, second);
+        for token in [first, second] {
+            // 256 bits of entropy in unpadded base64.
+            assert_eq!(token.len(), 43);
+            assert!(token
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_'));
+        }
+    }
+}
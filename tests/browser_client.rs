@@ -0,0 +1,100 @@
+use actix_csrf::extractor::{Csrf, CsrfHeader, CsrfToken};
+use actix_csrf::test::{app_with_csrf, roundtrip_check, with_csrf_disabled, BrowserClient};
+use actix_csrf::{CsrfAction, CsrfError, CsrfMiddleware};
+
+use actix_http::StatusCode;
+use actix_web::http::header::{HeaderValue, X_FORWARDED_PROTO};
+use actix_web::http::Method;
+use actix_web::test::{read_body, TestRequest};
+use actix_web::FromRequest;
+use actix_web::{web, HttpResponse};
+use rand::prelude::StdRng;
+
+const COOKIE_NAME: &str = "__Host-Csrf-Token";
+
+#[actix_web::test]
+async fn issue_post_rotate_cycle() {
+    let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/");
+    let app = app_with_csrf(csrf, |cfg| {
+        cfg.service(
+            web::resource("/")
+                .route(web::get().to(|token: CsrfToken| async move {
+                    HttpResponse::Ok().body(token.into_inner())
+                }))
+                .route(web::post().to(|_: Csrf<CsrfHeader>| async {
+                    let mut res = HttpResponse::Ok().finish();
+                    res.extensions_mut().insert(CsrfAction::Reissue);
+                    res
+                })),
+        );
+    })
+    .await;
+    let mut client = BrowserClient::new();
+
+    // Issue: the token in the page matches the stored cookie.
+    let resp = client.call(&app, TestRequest::with_uri("/")).await;
+    let token = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+    assert_eq!(client.cookie(COOKIE_NAME).unwrap().value(), token);
+
+    // Post: the stored cookie is sent along with the submitted token.
+    let post = |token: &str| {
+        TestRequest::post()
+            .uri("/")
+            .insert_header(("csrf-token", token.to_owned()))
+    };
+    let resp = client.call(&app, post(&token)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Rotate: the reissued cookie replaces the old one, retiring the old token.
+    let rotated = client.cookie(COOKIE_NAME).unwrap().value().to_owned();
+    assert_ne!(rotated, token);
+    let resp = client.call(&app, post(&token)).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let resp = client.call(&app, post(&rotated)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn validation_is_skipped_only_inside_scope() {
+    let app = app_with_csrf(CsrfMiddleware::<StdRng>::new(), |cfg| {
+        cfg.route(
+            "/",
+            web::post().to(|_: Csrf<CsrfHeader>| HttpResponse::Ok()),
+        );
+    })
+    .await;
+    let mut client = BrowserClient::new();
+    let forged = || {
+        TestRequest::post()
+            .uri("/")
+            .insert_header(("csrf-token", "forged"))
+    };
+
+    let resp = client.call(&app, forged()).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let resp = with_csrf_disabled(|| client.call(&app, forged())).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = client.call(&app, forged()).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // The extractor honors the scope without the middleware too.
+    let req = forged().to_http_request();
+    assert!(Csrf::<CsrfHeader>::extract(&req).await.is_err());
+    let extracted = with_csrf_disabled(|| Csrf::<CsrfHeader>::extract(&req)).await;
+    assert!(extracted.unwrap().validate("forged"));
+}
+
+#[actix_web::test]
+async fn roundtrip_check_reports_rejections() {
+    let csrf = || CsrfMiddleware::<StdRng>::new().require_secure_transport(true);
+    let https = [(X_FORWARDED_PROTO, HeaderValue::from_static("https"))];
+
+    let result = roundtrip_check(csrf(), Method::POST, "/submit", https).await;
+    assert_eq!(result, Ok(()));
+
+    // The `Secure` cookie is refused over plain HTTP, so no token is issued.
+    let result = roundtrip_check(csrf(), Method::POST, "/submit", []).await;
+    assert_eq!(result, Err(CsrfError::TokenNotIssued));
+}
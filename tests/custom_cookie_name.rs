@@ -1,59 +1,64 @@
 #![cfg(test)]
 
-use std::str::FromStr;
-
 use actix_csrf::extractor::{Csrf, CsrfCookieConfig, CsrfGuarded, CsrfToken};
+use actix_csrf::test::{app_with_csrf, BrowserClient};
 use actix_csrf::CsrfMiddleware;
 
 use actix_http::{Request, StatusCode};
 use actix_web::body::{BoxBody, MessageBody};
 use actix_web::dev::{Service, ServiceResponse};
-use actix_web::http::header::SET_COOKIE;
+use actix_web::http::header::HOST;
 use actix_web::http::Method;
-use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::test::TestRequest;
 use actix_web::web::Form;
 use actix_web::HttpResponse;
-use actix_web::{get, post, App, Responder};
+use actix_web::{get, post, Responder};
 use anyhow::{Context, Result};
 use cookie::Cookie;
 use rand::prelude::StdRng;
 use serde::{Deserialize, Serialize};
 
+/// The header the proxy uses to report the cookie name it exposes.
+const PROXY_COOKIE_NAME: &str = "x-proxy-cookie-name";
+
 #[actix_web::test]
 async fn get_request_returns_double_token() -> Result<()> {
     let csrf = CsrfMiddleware::<StdRng>::new()
         .set_cookie(Method::GET, "/login")
         .cookie_name("Custom-Cookie-Name");
-    let service = init_service(
-        App::new()
-            .app_data(CsrfCookieConfig::new("Custom-Cookie-Name".to_owned()))
-            .wrap(csrf)
-            .service(request_csrf),
-    )
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.app_data(CsrfCookieConfig::new("Custom-Cookie-Name".to_owned()))
+            .service(request_csrf);
+    })
     .await;
+    let mut client = BrowserClient::new();
 
-    let (resp, cookie) = get_cookie(&service).await?;
+    let (resp, cookie) = get_cookie(&mut client, &service, "Custom-Cookie-Name").await?;
 
     assert_eq!(cookie.value(), resp.into_body().try_into_bytes().unwrap());
 
     Ok(())
 }
 
-async fn get_cookie<S, E>(service: &S) -> Result<(ServiceResponse, Cookie<'static>)>
+/// Loads the login page, returning the response and the stored cookie.
+async fn get_cookie<S, E>(
+    client: &mut BrowserClient,
+    service: &S,
+    name: &str,
+) -> Result<(ServiceResponse, Cookie<'static>)>
 where
     S: Service<Request, Response = ServiceResponse<BoxBody>, Error = E>,
     E: std::fmt::Debug,
 {
-    let req = TestRequest::with_uri("/login").to_request();
-    let resp = call_service(&service, req).await;
-    let set_cookie_header = resp
-        .headers()
-        .get(SET_COOKIE)
-        .context("set cookie header missing")?
-        .to_str()
-        .context("csrf token not base64")?;
-    let cookie = Cookie::from_str(set_cookie_header)?;
-    Ok((resp, cookie.into_owned()))
+    let resp = client.call(service, TestRequest::with_uri("/login")).await;
+    let cookie = client.cookie(name).context("csrf cookie missing")?.clone();
+    Ok((resp, cookie))
+}
+
+fn login_request(token: &str) -> TestRequest {
+    TestRequest::post().uri("/login").set_form(LoginForm {
+        csrf_token: CsrfToken::test_create(token.to_owned()),
+    })
 }
 
 #[actix_web::test]
@@ -61,26 +66,17 @@ async fn post_request_is_guarded() -> Result<()> {
     let csrf = CsrfMiddleware::<StdRng>::new()
         .set_cookie(Method::GET, "/login")
         .cookie_name("Custom-Cookie-Name");
-    let service = init_service(
-        App::new()
-            .app_data(CsrfCookieConfig::new("Custom-Cookie-Name".to_owned()))
-            .wrap(csrf)
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.app_data(CsrfCookieConfig::new("Custom-Cookie-Name".to_owned()))
             .service(request_csrf)
-            .service(login),
-    )
+            .service(login);
+    })
     .await;
+    let mut client = BrowserClient::new();
 
-    let (_, cookie) = get_cookie(&service).await?;
-
-    let req = TestRequest::post()
-        .uri("/login")
-        .cookie(cookie.clone())
-        .set_form(LoginForm {
-            csrf_token: CsrfToken::test_create(cookie.value().to_owned()),
-        })
-        .to_request();
+    let (_, cookie) = get_cookie(&mut client, &service, "Custom-Cookie-Name").await?;
 
-    let resp = call_service(&service, req).await;
+    let resp = client.call(&service, login_request(cookie.value())).await;
     assert_eq!(resp.status(), StatusCode::OK);
     Ok(())
 }
@@ -90,30 +86,197 @@ async fn custom_cookie_name_shortcut_works() -> Result<()> {
     let csrf = CsrfMiddleware::<StdRng>::new()
         .set_cookie(Method::GET, "/login")
         .cookie_name("Custom-Cookie-Name");
-    let service = init_service(
-        App::new()
-            .app_data(csrf.cookie_config())
-            .wrap(csrf)
+    let cookie_config = csrf.cookie_config();
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.app_data(cookie_config)
             .service(request_csrf)
-            .service(login),
-    )
+            .service(login);
+    })
+    .await;
+    let mut client = BrowserClient::new();
+
+    let (_, cookie) = get_cookie(&mut client, &service, "Custom-Cookie-Name").await?;
+
+    let resp = client.call(&service, login_request(cookie.value())).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    Ok(())
+}
+
+#[actix_web::test]
+async fn cookie_name_applies_to_extractors() -> Result<()> {
+    let csrf = CsrfMiddleware::<StdRng>::new()
+        .set_cookie(Method::GET, "/login")
+        .cookie_name("myAppCsrf")
+        .try_build()?;
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf).service(login);
+    })
+    .await;
+    let mut client = BrowserClient::new();
+
+    let (resp, cookie) = get_cookie(&mut client, &service, "myAppCsrf").await?;
+    assert_eq!(cookie.value(), resp.into_body().try_into_bytes().unwrap());
+
+    let resp = client.call(&service, login_request(cookie.value())).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The default cookie name is no longer accepted.
+    let mut client = BrowserClient::new();
+    client.add_cookie(Cookie::new("__Host-Csrf-Token", cookie.value().to_owned()));
+    let resp = client.call(&service, login_request(cookie.value())).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    Ok(())
+}
+
+#[actix_web::test]
+async fn cookie_name_fn_is_per_host() -> Result<()> {
+    let csrf = CsrfMiddleware::<StdRng>::new()
+        .set_cookie(Method::GET, "/login")
+        .cookie_name_fn(|req| {
+            let host = req.connection_info().host().to_owned();
+            let tenant = host.split('.').next().unwrap_or_default();
+            format!("{tenant}-Csrf-Token")
+        });
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf).service(login);
+    })
+    .await;
+
+    let mut tenant1 = BrowserClient::new();
+    let mut tenant2 = BrowserClient::new();
+    let token1 =
+        get_token_for_host(&mut tenant1, &service, "tenant1", "tenant1-Csrf-Token").await?;
+    let token2 =
+        get_token_for_host(&mut tenant2, &service, "tenant2", "tenant2-Csrf-Token").await?;
+
+    for (host, client, token) in [
+        ("tenant1.example.com", &mut tenant1, &token1),
+        ("tenant2.example.com", &mut tenant2, &token2),
+    ] {
+        let req = login_request(token).insert_header((HOST, host));
+        let resp = client.call(&service, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    // Tenant 1's cookie is not read for tenant 2.
+    let req = login_request(&token1).insert_header((HOST, "tenant2.example.com"));
+    let resp = tenant1.call(&service, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn cookie_name_fn_strips_proxy_prefix() -> Result<()> {
+    // The proxy tells the app which cookie name it rewrote the app's cookie
+    // to. The app maps the rewritten name back to the real one.
+    let csrf = CsrfMiddleware::<StdRng>::new()
+        .set_cookie(Method::GET, "/login")
+        .cookie_name_fn(|req| {
+            let rewritten = req
+                .headers()
+                .get(PROXY_COOKIE_NAME)
+                .and_then(|name| name.to_str().ok())
+                .unwrap_or("csrf-token");
+            rewritten
+                .strip_prefix("proxy_")
+                .unwrap_or(rewritten)
+                .to_owned()
+        });
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf).service(login);
+    })
     .await;
+    let mut client = BrowserClient::new();
+
+    // Issuance uses the resolved name.
+    let req =
+        TestRequest::with_uri("/login").insert_header((PROXY_COOKIE_NAME, "proxy_csrf-token"));
+    client.call(&service, req).await;
+    assert!(client.cookie("proxy_csrf-token").is_none());
+    let token = client
+        .cookie("csrf-token")
+        .context("csrf cookie missing")?
+        .value()
+        .to_owned();
+
+    // Extraction reads the resolved name...
+    let req = login_request(&token).insert_header((PROXY_COOKIE_NAME, "proxy_csrf-token"));
+    let resp = client.call(&service, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
 
-    let (_, cookie) = get_cookie(&service).await?;
+    // ...and ignores a cookie under the rewritten name.
+    let mut client = BrowserClient::new();
+    client.add_cookie(Cookie::new("proxy_csrf-token", token.clone()));
+    let req = login_request(&token).insert_header((PROXY_COOKIE_NAME, "proxy_csrf-token"));
+    let resp = client.call(&service, req).await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn invalid_cookie_name_fn_falls_back() -> Result<()> {
+    let csrf = CsrfMiddleware::<StdRng>::new()
+        .set_cookie(Method::GET, "/login")
+        .cookie_name("Custom-Cookie-Name")
+        .cookie_name_fn(|_| "not a valid name".to_owned());
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf);
+    })
+    .await;
 
-    let req = TestRequest::post()
-        .uri("/login")
-        .cookie(cookie.clone())
-        .set_form(LoginForm {
-            csrf_token: CsrfToken::test_create(cookie.value().to_owned()),
-        })
-        .to_request();
+    get_cookie(&mut BrowserClient::new(), &service, "Custom-Cookie-Name").await?;
 
-    let resp = call_service(&service, req).await;
+    Ok(())
+}
+
+#[actix_web::test]
+async fn legacy_cookie_name_is_migrated() -> Result<()> {
+    let csrf = CsrfMiddleware::<StdRng>::new()
+        .set_cookie(Method::GET, "/login")
+        .cookie_name("__Host-Csrf-Token")
+        .legacy_cookie_names(&["Old-Csrf-Token"]);
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(login);
+    })
+    .await;
+    let mut client = BrowserClient::new();
+    client.add_cookie(Cookie::new("Old-Csrf-Token", "legacy-token"));
+
+    let resp = client.call(&service, login_request("legacy-token")).await;
     assert_eq!(resp.status(), StatusCode::OK);
+
+    assert!(
+        client.cookie("Old-Csrf-Token").is_none(),
+        "legacy cookie not removed"
+    );
+    let current = client
+        .cookie("__Host-Csrf-Token")
+        .context("current cookie not issued")?;
+    assert_eq!(current.value(), "legacy-token");
+
     Ok(())
 }
 
+/// Loads the login page for the tenant, returning the stored token.
+async fn get_token_for_host<S, E>(
+    client: &mut BrowserClient,
+    service: &S,
+    tenant: &str,
+    name: &str,
+) -> Result<String>
+where
+    S: Service<Request, Response = ServiceResponse<BoxBody>, Error = E>,
+    E: std::fmt::Debug,
+{
+    let req =
+        TestRequest::with_uri("/login").insert_header((HOST, format!("{tenant}.example.com")));
+    client.call(service, req).await;
+    let cookie = client.cookie(name).context("csrf cookie missing")?;
+    Ok(cookie.value().to_owned())
+}
+
 /// Returns a simple login form with a CSRF token.
 #[get("/login")]
 async fn request_csrf(token: CsrfToken) -> impl Responder {
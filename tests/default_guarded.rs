@@ -1,8 +1,7 @@
 #![cfg(test)]
 
-use std::str::FromStr;
-
 use actix_csrf::extractor::{Csrf, CsrfGuarded, CsrfToken};
+use actix_csrf::test::{app_with_csrf, BrowserClient};
 use actix_csrf::CsrfMiddleware;
 
 use actix_http::{Request, StatusCode};
@@ -10,80 +9,146 @@ use actix_web::body::{BoxBody, MessageBody};
 use actix_web::dev::{Service, ServiceResponse};
 use actix_web::http::header::SET_COOKIE;
 use actix_web::http::Method;
-use actix_web::test::{call_service, init_service, TestRequest};
-use actix_web::web::Form;
+use actix_web::test::TestRequest;
+use actix_web::web::{self, Form};
 use actix_web::HttpResponse;
-use actix_web::{get, post, App, Responder};
+use actix_web::{get, post, Responder};
 use anyhow::{Context, Result};
-use cookie::Cookie;
 use rand::prelude::StdRng;
 use serde::{Deserialize, Serialize};
 
+const COOKIE_NAME: &str = "__Host-Csrf-Token";
+
 #[actix_web::test]
 async fn get_request_returns_double_token() -> Result<()> {
     let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/login");
-    let service = init_service(App::new().wrap(csrf).service(request_csrf)).await;
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf);
+    })
+    .await;
+    let mut client = BrowserClient::new();
 
-    let (resp, cookie) = get_cookie(&service).await?;
+    let (resp, token) = get_token(&mut client, &service).await?;
 
-    assert_eq!(cookie.value(), resp.into_body().try_into_bytes().unwrap());
+    assert_eq!(token, resp.into_body().try_into_bytes().unwrap());
 
     Ok(())
 }
 
-async fn get_cookie<S, E>(service: &S) -> Result<(ServiceResponse, Cookie<'static>)>
+/// Loads the login page, returning the response and the stored token.
+async fn get_token<S, E>(
+    client: &mut BrowserClient,
+    service: &S,
+) -> Result<(ServiceResponse, String)>
 where
     S: Service<Request, Response = ServiceResponse<BoxBody>, Error = E>,
     E: std::fmt::Debug,
 {
-    let req = TestRequest::with_uri("/login").to_request();
-    let resp = call_service(&service, req).await;
-    let set_cookie_header = resp
-        .headers()
-        .get(SET_COOKIE)
-        .context("set cookie header missing")?
-        .to_str()
-        .context("csrf token not base64")?;
-    let cookie = Cookie::from_str(set_cookie_header)?;
-    Ok((resp, cookie.into_owned()))
+    let resp = client.call(service, TestRequest::with_uri("/login")).await;
+    let token = client
+        .cookie(COOKIE_NAME)
+        .context("csrf cookie missing")?
+        .value()
+        .to_owned();
+    Ok((resp, token))
+}
+
+fn login_request(token: &str) -> TestRequest {
+    TestRequest::post().uri("/login").set_form(LoginForm {
+        csrf_token: CsrfToken::test_create(token.to_owned()),
+    })
 }
 
 #[actix_web::test]
 async fn post_request_is_guarded() -> Result<()> {
     let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/login");
-    let service = init_service(App::new().wrap(csrf).service(request_csrf).service(login)).await;
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf).service(login);
+    })
+    .await;
+    let mut client = BrowserClient::new();
 
-    let (_, cookie) = get_cookie(&service).await?;
+    let (_, token) = get_token(&mut client, &service).await?;
 
-    let req = TestRequest::post()
-        .uri("/login")
-        .cookie(cookie.clone())
-        .set_form(LoginForm {
-            csrf_token: CsrfToken::test_create(cookie.value().to_owned()),
-        })
-        .to_request();
+    let resp = client.call(&service, login_request(&token)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    Ok(())
+}
 
-    let resp = call_service(&service, req).await;
+#[actix_web::test]
+async fn only_set_cookie_route_issues_token() -> Result<()> {
+    let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/login");
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf).service(login);
+    })
+    .await;
+    let mut client = BrowserClient::new();
+
+    let (_, token) = get_token(&mut client, &service).await?;
+
+    // Protected requests validate against the stored cookie without a new one.
+    let resp = client.call(&service, login_request(&token)).await;
     assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get(SET_COOKIE).is_none());
+
+    let resp = client.call(&service, TestRequest::with_uri("/other")).await;
+    assert!(resp.headers().get(SET_COOKIE).is_none());
     Ok(())
 }
 
 #[actix_web::test]
 async fn missing_cookie_fails() -> Result<()> {
     let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/login");
-    let service = init_service(App::new().wrap(csrf).service(request_csrf).service(login)).await;
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf).service(login);
+    })
+    .await;
 
-    let (_, cookie) = get_cookie(&service).await?;
+    let (_, token) = get_token(&mut BrowserClient::new(), &service).await?;
 
-    let req = TestRequest::post()
-        .uri("/login")
-        .set_form(LoginForm {
-            csrf_token: CsrfToken::test_create(cookie.value().to_owned()),
-        })
-        .to_request();
+    // A different client has the token, but not the cookie.
+    let resp = BrowserClient::new()
+        .call(&service, login_request(&token))
+        .await;
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    Ok(())
+}
 
-    let resp = call_service(&service, req).await;
+#[actix_web::test]
+async fn default_service_uses_its_own_token_location() -> Result<()> {
+    let csrf = CsrfMiddleware::<StdRng>::new().set_cookie(Method::GET, "/login");
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.service(request_csrf)
+            .service(login)
+            .default_service(web::post().to(legacy_fallback));
+    })
+    .await;
+    let mut client = BrowserClient::new();
+
+    let (_, token) = get_token(&mut client, &service).await?;
+
+    // Routed requests use the first-class form field...
+    let resp = client.call(&service, login_request(&token)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // ...while the catch-all uses the legacy backend's field name.
+    let legacy_request = || {
+        TestRequest::post()
+            .uri("/legacy/comment")
+            .set_form(LegacyForm {
+                authenticity_token: CsrfToken::test_create(token.clone()),
+            })
+    };
+    let resp = client.call(&service, legacy_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = login_request(&token).uri("/legacy/comment");
+    let resp = client.call(&service, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = BrowserClient::new().call(&service, legacy_request()).await;
     assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
     Ok(())
 }
 
@@ -110,3 +175,19 @@ async fn login(_: Csrf<Form<LoginForm>>) -> impl Responder {
     // as legitimate.
     HttpResponse::Ok().finish()
 }
+
+#[derive(Serialize, Deserialize)]
+struct LegacyForm {
+    authenticity_token: CsrfToken,
+}
+
+impl CsrfGuarded for LegacyForm {
+    fn csrf_token(&self) -> &CsrfToken {
+        &self.authenticity_token
+    }
+}
+
+/// Stands in for proxying unmatched requests to a legacy backend.
+async fn legacy_fallback(_: Csrf<Form<LegacyForm>>) -> impl Responder {
+    HttpResponse::Ok().finish()
+}
@@ -0,0 +1,78 @@
+#![cfg(test)]
+
+use actix_csrf::extractor::{Csrf, CsrfGuarded, CsrfHeader, CsrfHeaderConfig, CsrfToken};
+use actix_csrf::test::{app_with_csrf, BrowserClient};
+use actix_csrf::CsrfMiddleware;
+
+use actix_http::StatusCode;
+use actix_web::http::Method;
+use actix_web::test::TestRequest;
+use actix_web::web::Form;
+use actix_web::HttpResponse;
+use actix_web::{get, post, Responder};
+use anyhow::{Context, Result};
+use cookie::SameSite;
+use rand::prelude::StdRng;
+use serde::{Deserialize, Serialize};
+
+#[actix_web::test]
+async fn django_header_and_form_field_validate() -> Result<()> {
+    let csrf = CsrfMiddleware::<StdRng>::django_compat().set_cookie(Method::GET, "/login");
+    let cookie_config = csrf.cookie_config();
+    let service = app_with_csrf(csrf, |cfg| {
+        cfg.app_data(cookie_config)
+            .app_data(CsrfHeaderConfig::django_compat())
+            .service(request_csrf)
+            .service(login)
+            .service(api);
+    })
+    .await;
+    let mut client = BrowserClient::new();
+
+    client.call(&service, TestRequest::with_uri("/login")).await;
+    let cookie = client.cookie("csrftoken").context("csrf cookie missing")?;
+    assert_eq!(cookie.http_only(), None);
+    assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+    let token = cookie.value().to_owned();
+
+    let req = TestRequest::post()
+        .uri("/api")
+        .insert_header(("X-CSRFToken", token.clone()));
+    let resp = client.call(&service, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let req = TestRequest::post().uri("/login").set_form(LoginForm {
+        csrfmiddlewaretoken: CsrfToken::test_create(token),
+    });
+    let resp = client.call(&service, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    Ok(())
+}
+
+/// Returns a simple login form with a CSRF token.
+#[get("/login")]
+async fn request_csrf(token: CsrfToken) -> impl Responder {
+    HttpResponse::Ok().body(token.into_inner())
+}
+
+#[derive(Serialize, Deserialize)]
+struct LoginForm {
+    csrfmiddlewaretoken: CsrfToken,
+}
+
+impl CsrfGuarded for LoginForm {
+    fn csrf_token(&self) -> &CsrfToken {
+        &self.csrfmiddlewaretoken
+    }
+}
+
+#[post("/login")]
+async fn login(_: Csrf<Form<LoginForm>>) -> impl Responder {
+    HttpResponse::Ok().finish()
+}
+
+#[post("/api")]
+async fn api(_: Csrf<CsrfHeader>) -> impl Responder {
+    HttpResponse::Ok().finish()
+}